@@ -0,0 +1,55 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+// The prebuilt glslang libraries are C++ and don't link the C++ runtime themselves.
+extern crate link_cplusplus;
+
+#[path = "src/glsl.rs"]
+mod glsl;
+
+
+const SHADER_DIR: &str = "shaders";
+
+
+/// Compiles every GLSL shader under `shaders/` to `$OUT_DIR/<name>.spv`.
+fn main() {
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+
+    // The app links glslang too, for shader hot reloading.
+    if env::var("CARGO_CFG_TARGET_OS").unwrap() == "linux" {
+        println!("cargo:rustc-link-lib=stdc++");
+    }
+
+    println!("cargo:rerun-if-changed={}", SHADER_DIR);
+    println!("cargo:rerun-if-changed=src/glsl.rs");
+
+    let mut failures = vec![];
+    for entry in fs::read_dir(SHADER_DIR).unwrap() {
+        let path = entry.unwrap().path();
+        if glsl::get_shader_type(&path).is_none() {
+            continue;
+        }
+
+        println!("cargo:rerun-if-changed={}", path.display());
+
+        let mut name = path.file_name().unwrap().to_os_string();
+        name.push(".spv");
+
+        let result = glsl::compile_shader(&path)
+            .and_then(|b| fs::write(out_dir.join(name), b).map_err(|e| e.to_string()));
+
+        if let Err(error) = result {
+            failures.push(format!("{}:\n{}", path.display(), error.trim_end()));
+        }
+    }
+
+    if !failures.is_empty() {
+        eprintln!("Failed to compile {} shader(s):\n", failures.len());
+        for failure in &failures {
+            eprintln!("{}\n", failure);
+        }
+
+        std::process::exit(1);
+    }
+}
@@ -1,3 +0,0 @@
-h:/Coding/SDKs/VulkanSDK/Bin/glslc.exe shader.vert -o vert.spv
-h:/Coding/SDKs/VulkanSDK/Bin/glslc.exe shader.frag -o frag.spv
-pause
\ No newline at end of file
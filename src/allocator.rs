@@ -0,0 +1,268 @@
+//! Sub-allocation of device memory out of large per-memory-type blocks.
+
+use std::ptr::NonNull;
+
+use anyhow::{anyhow, Result};
+use log::*;
+use vulkanalia::vk::{self, DeviceV1_0, Handle, HasBuilder, InstanceV1_0};
+use vulkanalia::{Device, Instance};
+
+
+/// The size of the blocks most allocations are carved out of.
+const BLOCK_SIZE: vk::DeviceSize = 64 * 1024 * 1024;
+
+
+/// A range of device memory handed out by the [`Allocator`].
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Allocation {
+    pub memory: vk::DeviceMemory,
+    pub offset: vk::DeviceSize,
+    pub size: vk::DeviceSize,
+    mapped: Option<NonNull<u8>>,
+}
+
+impl Allocation {
+    /// Gets a pointer to the start of this allocation in host memory.
+    ///
+    /// Host-visible blocks stay mapped for their whole lifetime, so there is
+    /// no need to map or unmap individual allocations.
+    pub fn mapped_ptr(&self) -> Result<*mut u8> {
+        self.mapped
+            .map(|p| p.as_ptr())
+            .ok_or_else(|| anyhow!("Allocation is not host visible."))
+    }
+}
+
+/// Memory usage of the [`Allocator`].
+#[derive(Copy, Clone, Debug, Default)]
+pub struct AllocatorStats {
+    /// The number of `vk::DeviceMemory` objects currently allocated.
+    pub block_count: usize,
+    /// The number of live sub-allocations.
+    pub allocation_count: usize,
+    /// The total size of all blocks.
+    pub reserved_bytes: vk::DeviceSize,
+    /// The total size of all live sub-allocations.
+    pub used_bytes: vk::DeviceSize,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Range {
+    offset: vk::DeviceSize,
+    size: vk::DeviceSize,
+    linear: bool,
+}
+
+#[derive(Clone, Debug)]
+struct Block {
+    memory: vk::DeviceMemory,
+    memory_type_index: u32,
+    size: vk::DeviceSize,
+    mapped: Option<NonNull<u8>>,
+    /// The live sub-allocations, sorted by offset.
+    ranges: Vec<Range>,
+}
+
+/// Hands out sub-allocations of large device memory blocks, one set of blocks per memory type.
+#[derive(Clone, Debug, Default)]
+pub struct Allocator {
+    memory_properties: vk::PhysicalDeviceMemoryProperties,
+    buffer_image_granularity: vk::DeviceSize,
+    blocks: Vec<Block>,
+}
+
+impl Allocator {
+    pub unsafe fn new(instance: &Instance, physical_device: vk::PhysicalDevice) -> Self {
+        let memory_properties = instance.get_physical_device_memory_properties(physical_device);
+        let properties = instance.get_physical_device_properties(physical_device);
+
+        Self {
+            memory_properties,
+            buffer_image_granularity: properties.limits.buffer_image_granularity.max(1),
+            blocks: vec![],
+        }
+    }
+
+    pub fn get_memory_type_index(
+        &self,
+        properties: vk::MemoryPropertyFlags,
+        requirements: vk::MemoryRequirements,
+    ) -> Result<u32> {
+        let memory = &self.memory_properties;
+        (0..memory.memory_type_count)
+            .find(|i| {
+                let suitable = (requirements.memory_type_bits & (1 << i)) != 0;
+                let memory_type = memory.memory_types[*i as usize];
+                suitable && memory_type.property_flags.contains(properties)
+            })
+            .ok_or_else(|| anyhow!("Failed to find suitable memory type."))
+    }
+
+    /// Allocates memory for a resource.
+    ///
+    /// `linear` is `true` for buffers and linearly tiled images, which must be
+    /// kept `bufferImageGranularity` apart from optimally tiled images.
+    pub unsafe fn allocate(
+        &mut self,
+        device: &Device,
+        requirements: vk::MemoryRequirements,
+        properties: vk::MemoryPropertyFlags,
+        linear: bool,
+    ) -> Result<Allocation> {
+        let memory_type_index = self.get_memory_type_index(properties, requirements)?;
+        let range = |offset| Range { offset, size: requirements.size, linear };
+
+        for block in self.blocks.iter_mut().filter(|b| b.memory_type_index == memory_type_index) {
+            if let Some(offset) = block.find_offset(requirements, linear, self.buffer_image_granularity) {
+                return Ok(block.insert(range(offset)));
+            }
+        }
+
+        // Resources larger than a block get a block of their own.
+        let heap_index = self.memory_properties.memory_types[memory_type_index as usize].heap_index;
+        let heap_size = self.memory_properties.memory_heaps[heap_index as usize].size;
+        let size = requirements.size.max(BLOCK_SIZE.min(heap_size / 8));
+
+        let mut block = self.create_block(device, memory_type_index, size)?;
+        let allocation = block.insert(range(0));
+        self.blocks.push(block);
+
+        Ok(allocation)
+    }
+
+    /// Returns an allocation to its block.
+    ///
+    /// One empty block per memory type is kept around so that short-lived
+    /// allocations such as staging buffers don't allocate a block every time.
+    pub unsafe fn free(&mut self, device: &Device, allocation: Allocation) {
+        if allocation.memory.is_null() {
+            return;
+        }
+
+        let Some(index) = self.blocks.iter().position(|b| b.memory == allocation.memory) else {
+            warn!("Freeing an allocation that does not belong to the allocator.");
+            return;
+        };
+
+        let block = &mut self.blocks[index];
+        block.ranges.retain(|r| r.offset != allocation.offset);
+
+        if !block.ranges.is_empty() {
+            return;
+        }
+
+        let memory_type_index = block.memory_type_index;
+        let spare = self.blocks
+            .iter()
+            .enumerate()
+            .any(|(i, b)| i != index && b.memory_type_index == memory_type_index && b.ranges.is_empty());
+
+        if spare || self.blocks[index].size > BLOCK_SIZE {
+            let block = self.blocks.remove(index);
+            device.free_memory(block.memory, None);
+        }
+    }
+
+    pub fn stats(&self) -> AllocatorStats {
+        AllocatorStats {
+            block_count: self.blocks.len(),
+            allocation_count: self.blocks.iter().map(|b| b.ranges.len()).sum(),
+            reserved_bytes: self.blocks.iter().map(|b| b.size).sum(),
+            used_bytes: self.blocks.iter().flat_map(|b| &b.ranges).map(|r| r.size).sum(),
+        }
+    }
+
+    /// Frees every block, including any allocations that were never freed.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        let stats = self.stats();
+        if stats.allocation_count > 0 {
+            warn!("Destroying allocator with {} live allocation(s) ({} bytes).", stats.allocation_count, stats.used_bytes);
+        }
+
+        self.blocks.drain(..).for_each(|b| device.free_memory(b.memory, None));
+    }
+
+    unsafe fn create_block(
+        &self,
+        device: &Device,
+        memory_type_index: u32,
+        size: vk::DeviceSize,
+    ) -> Result<Block> {
+        let info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(size)
+            .memory_type_index(memory_type_index);
+
+        let memory = device.allocate_memory(&info, None)?;
+
+        let flags = self.memory_properties.memory_types[memory_type_index as usize].property_flags;
+        let mapped = if flags.contains(vk::MemoryPropertyFlags::HOST_VISIBLE) {
+            let pointer = device.map_memory(memory, 0, vk::WHOLE_SIZE as u64, vk::MemoryMapFlags::empty())?;
+            NonNull::new(pointer.cast::<u8>())
+        } else {
+            None
+        };
+
+        debug!("Allocated {} byte block for memory type {}.", size, memory_type_index);
+
+        Ok(Block { memory, memory_type_index, size, mapped, ranges: vec![] })
+    }
+}
+
+impl Block {
+    /// Finds the first offset a resource fits at, or `None` if the block is full.
+    fn find_offset(
+        &self,
+        requirements: vk::MemoryRequirements,
+        linear: bool,
+        granularity: vk::DeviceSize,
+    ) -> Option<vk::DeviceSize> {
+        let alignment = requirements.alignment.max(1);
+        let page = |offset: vk::DeviceSize| offset / granularity;
+
+        let mut previous: Option<&Range> = None;
+        for index in 0..=self.ranges.len() {
+            let next = self.ranges.get(index);
+
+            let start = previous.map_or(0, |p| p.offset + p.size);
+            let mut offset = align_up(start, alignment);
+
+            // Linear and non-linear resources may not share a granularity page.
+            if let Some(previous) = previous {
+                if previous.linear != linear && page(previous.offset + previous.size - 1) == page(offset) {
+                    offset = align_up(offset, granularity);
+                }
+            }
+
+            let end = offset + requirements.size;
+            let fits = match next {
+                Some(next) => end <= next.offset && (next.linear == linear || page(end - 1) != page(next.offset)),
+                None => end <= self.size,
+            };
+
+            if fits {
+                return Some(offset);
+            }
+
+            previous = next;
+        }
+
+        None
+    }
+
+    fn insert(&mut self, range: Range) -> Allocation {
+        let index = self.ranges.partition_point(|r| r.offset < range.offset);
+        self.ranges.insert(index, range);
+
+        Allocation {
+            memory: self.memory,
+            offset: range.offset,
+            size: range.size,
+            // SAFETY: the offset lies within the mapped block.
+            mapped: self.mapped.map(|p| unsafe { NonNull::new_unchecked(p.as_ptr().add(range.offset as usize)) }),
+        }
+    }
+}
+
+fn align_up(value: vk::DeviceSize, alignment: vk::DeviceSize) -> vk::DeviceSize {
+    value.div_ceil(alignment) * alignment
+}
@@ -1,105 +1,195 @@
 use crate::app_data::AppData;
-use crate::mesh::load_model;
-use crate::syncronization::MAX_FRAMES_IN_FLIGHT;
 use crate::device::{create_logical_device,pick_physical_device};
 use crate::swapchain::{create_swapchain,create_swapchain_image_views};
-use crate::command::{create_command_buffers,create_command_pools,create_render_pass,create_framebuffers};
-use crate::pipeline::create_pipeline;
+use crate::command::{build_render_graph, create_command_buffers, create_command_pools, create_render_graph, record_command_buffer, RecordingStats};
+use crate::pipeline::{create_pipeline, create_pipeline_layout, load_shaders};
+use crate::pipeline_cache::{create_pipeline_cache, save_pipeline_cache};
+use crate::profiler::create_profiler;
+use crate::stats::{create_statistics_queries, FrameStats};
+use crate::hot_reload::{reload_shaders, ShaderWatcher};
 use crate::syncronization::create_sync_objects;
-use crate::debug::VALIDATION_ENABLED;
 use crate::debug::VALIDATION_LAYER;
 use crate::PORTABILITY_MACOS_VERSION;
 use crate::debug::debug_callback;
-use crate::shader::{create_descriptor_pool, create_descriptor_set_layout, create_descriptor_sets, create_index_buffer, create_uniform_buffers, create_vertex_buffer, update_uniform_buffer};
-use crate::image::{create_depth_objects,create_texture_image,create_texture_image_view, create_texture_sampler};
+use crate::shader::{create_descriptor_pool, create_descriptor_set_layouts, create_descriptor_sets, create_material_descriptor_sets, create_uniform_buffers, update_uniform_buffer};
+use crate::scene::load_scene;
+use crate::upload::create_upload_context;
+use crate::camera::{Camera, CameraController};
+use crate::capabilities::{Feature, V1_3_0};
+use crate::headless::{create_offscreen_target, save_offscreen_png};
+use crate::owned::{OwnedInstance, SharedDevice, SharedInstance};
+use crate::settings::Settings;
 
 
-use std::time::Instant;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Result};
 use log::*;
 use vulkanalia::loader::{LibloadingLoader, LIBRARY};
 use vulkanalia::window as vk_window;
+use vulkanalia::Version;
 use vulkanalia::prelude::v1_0::*;
 
-use vulkanalia::vk::KhrSurfaceExtension;
-
 use vulkanalia::vk::ExtDebugUtilsExtension;
 use vulkanalia::vk::KhrSwapchainExtension;
 
 
+use winit::event::{ElementState, VirtualKeyCode, WindowEvent};
 use winit::window::Window;
 
 use std::collections::HashSet;
 
 
+/// How often the statistics in the window title are updated.
+const TITLE_UPDATE_INTERVAL: Duration = Duration::from_millis(500);
 
 
 // Our Vulkan app.
-#[derive(Clone, Debug)]
 pub struct App {
     entry: Entry,
-    instance: Instance,
+    instance: SharedInstance,
     data: AppData,
-    device: Device,
+    device: SharedDevice,
     
     frame: usize,
 
     pub resized: bool,
-    start: Instant,
+    /// When the previous frame was rendered, for moving the camera at a steady speed.
+    last_frame: Instant,
+    camera: Camera,
+    camera_controller: CameraController,
+    recording_stats: RecordingStats,
+    stats: FrameStats,
+    /// When the statistics were last shown in the window title.
+    title_updated: Instant,
+
+    shader_watcher: Option<ShaderWatcher>,
 }
 
 impl App {
     /// Creates our Vulkan app.
-    pub unsafe fn create(window: &Window) -> Result<Self> {
+    pub unsafe fn create(window: &Window, settings: Settings) -> Result<Self> {
         let loader = LibloadingLoader::new(LIBRARY)?;
         let entry = Entry::new(loader).map_err(|b| anyhow!("{}", b))?;
-        let mut data = AppData::default();
-        let instance = create_instance(window, &entry, &mut data)?;
-        data.surface = vk_window::create_surface(&instance, &window, &window)?;
+        let mut data = AppData { settings, ..Default::default() };
+        let instance = create_instance(Some(window), &entry, &mut data)?;
 
         pick_physical_device(&instance, &mut data)?;
-        let device = create_logical_device(&entry, &instance, &mut data)?;
+        let device = create_logical_device(&instance, &mut data)?;
         create_swapchain(window, &instance, &device, &mut data)?;
         create_swapchain_image_views(&device, &mut data)?;
-        create_render_pass(&instance, &device, &mut data)?;
-        create_descriptor_set_layout(&device, &mut data)?;
+
+        Self::create_resources(entry, instance, device, data)
+    }
+
+    /// Creates our Vulkan app without a window, rendering into an offscreen target.
+    pub unsafe fn create_headless(settings: Settings) -> Result<Self> {
+        let loader = LibloadingLoader::new(LIBRARY)?;
+        let entry = Entry::new(loader).map_err(|b| anyhow!("{}", b))?;
+        let (width, height) = (settings.width, settings.height);
+        let mut data = AppData { headless: true, settings, ..Default::default() };
+        let instance = create_instance(None, &entry, &mut data)?;
+
+        pick_physical_device(&instance, &mut data)?;
+        let device = create_logical_device(&instance, &mut data)?;
+        create_offscreen_target(&instance, &device, &mut data, width, height)?;
+
+        Self::create_resources(entry, instance, device, data)
+    }
+
+    /// Creates everything that does not depend on how frames are presented.
+    unsafe fn create_resources(
+        entry: Entry,
+        instance: SharedInstance,
+        device: SharedDevice,
+        mut data: AppData,
+    ) -> Result<Self> {
+        create_render_graph(&instance, &mut data)?;
+        build_render_graph(&device, &mut data)?;
+        load_shaders(&mut data)?;
+        create_descriptor_set_layouts(&device, &mut data)?;
+        create_pipeline_layout(&device, &mut data)?;
+        create_pipeline_cache(&instance, &device, &mut data)?;
         create_pipeline(&device, &mut data)?;
         create_command_pools(&instance, &device, &mut data)?;
-        create_depth_objects(&instance, &device, &mut data)?;
-        create_framebuffers(&device, &mut data)?;
-        create_texture_image(&instance, &device, &mut data)?;
-        create_texture_image_view(&device, &mut data)?;
-        create_texture_sampler(&device, &mut data)?;
-        load_model(&mut data)?;
-        create_vertex_buffer(&instance, &device, &mut data)?;
-        create_index_buffer(&instance, &device, &mut data)?;
+        // Uploads are tracked on the same timeline as frames.
+        create_sync_objects(&device, &mut data)?;
+        create_upload_context(&device, &mut data)?;
+        load_scene(&instance, &device, &mut data)?;
+        create_material_descriptor_sets(&device, &mut data)?;
         create_uniform_buffers(&instance, &device, &mut data)?;
         create_descriptor_pool(&device, &mut data)?;
         create_descriptor_sets(&device, &mut data)?;
         create_command_buffers(&device, &mut data)?;
-        create_sync_objects(&device, &mut data)?;
+        create_profiler(&instance, &device, &mut data)?;
+        create_statistics_queries(&device, &mut data)?;
 
-        
+        let camera = Camera::new(&data.settings);
 
-        Ok(Self { entry, instance, data, device , frame : 0, resized : false , start: Instant::now()})
-        
+        Ok(Self { entry, instance, data, device , frame : 0, resized : false , last_frame: Instant::now(), camera, camera_controller: CameraController::default(), recording_stats: RecordingStats::default(), stats: FrameStats::default(), title_updated: Instant::now(), shader_watcher: None })
+    }
+
+    /// The draws recorded for the last frame, and the pipeline statistics of
+    /// the last completed frame where the device supports them.
+    pub fn stats(&self) -> FrameStats {
+        self.stats
+    }
+
+    /// Starts recompiling shaders under `directory` and rebuilding pipelines when they change.
+    pub fn watch_shaders(&mut self, directory: &str) -> Result<()> {
+        self.shader_watcher = Some(ShaderWatcher::new(directory)?);
+        Ok(())
+    }
+
+    /// Passes window input to the camera.
+    ///
+    /// Releasing F toggles wireframe rendering where the device supports it.
+    pub unsafe fn handle_window_event(&mut self, event: &WindowEvent) -> Result<()> {
+        self.camera_controller.handle_event(&mut self.camera, event);
+
+        if let WindowEvent::KeyboardInput { input, .. } = event {
+            // Key presses repeat while held, releases don't.
+            if input.state == ElementState::Released && input.virtual_keycode == Some(VirtualKeyCode::F) {
+                self.toggle_wireframe()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    unsafe fn toggle_wireframe(&mut self) -> Result<()> {
+        if !self.data.capabilities.has_feature(Feature::FillModeNonSolid) {
+            warn!("Wireframe rendering is not supported by the device.");
+            return Ok(());
+        }
+
+        self.data.wireframe = !self.data.wireframe;
+        // Command buffers are recorded every frame, so the next frame draws with the new pipeline.
+        create_pipeline(&self.device, &mut self.data)
     }
 
     /// Renders a frame for our Vulkan app.
     pub unsafe fn render(&mut self, window: &Window) -> Result<()> {
 
-        self.device.wait_for_fences(
-            &[self.data.in_flight_fences[self.frame]],
-            true,
-            u64::MAX,
-        )?;
+        if let Some(watcher) = &mut self.shader_watcher {
+            let changed = watcher.poll();
+            if !changed.is_empty() && reload_shaders(&self.device, &mut self.data, &changed)? {
+                watcher.reloaded();
+            }
+        }
+
+        self.data.frame_sync.begin_frame(&self.device, self.frame)?;
+        self.data.profiler.read_frame(&self.device, self.frame)?;
+        if let Some(pipeline) = self.data.statistics.read_frame(&self.device, self.frame)? {
+            self.stats.pipeline = Some(pipeline);
+        }
 
-   
         let result = self.device.acquire_next_image_khr(
-            self.data.swapchain,
+            *self.data.swapchain,
             u64::MAX,
-            self.data.image_available_semaphores[self.frame],
+            *self.data.image_available_semaphores[self.frame],
             vk::Fence::null(),
         );
 
@@ -111,42 +201,38 @@ impl App {
         };
 
 
-        if !self.data.images_in_flight[image_index as usize].is_null() {
-            self.device.wait_for_fences(
-                &[self.data.images_in_flight[image_index as usize]],
-                true,
-                u64::MAX,
-            )?;
-        }
-        self.data.images_in_flight[image_index as usize] =
-            self.data.in_flight_fences[self.frame];
-
-            
-        update_uniform_buffer(&self.start, &self.device, &mut self.data, image_index)?;
-
-        let wait_semaphores = &[self.data.image_available_semaphores[self.frame]];
-        let wait_stages = &[vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
-        let command_buffers = &[self.data.command_buffers[image_index as usize]];
-        let signal_semaphores = &[self.data.render_finished_semaphores[self.frame]];
-        let submit_info = vk::SubmitInfo::builder()
-            .wait_semaphores(wait_semaphores)
-            .wait_dst_stage_mask(wait_stages)
-            .command_buffers(command_buffers)
-            .signal_semaphores(signal_semaphores);
-    
-        
+        self.data.frame_sync.wait_for_image(&self.device, image_index)?;
 
+        let now = Instant::now();
+        self.camera_controller.update(&mut self.camera, now - self.last_frame);
+        self.last_frame = now;
+        update_uniform_buffer(&self.camera, &self.data, image_index)?;
 
-        self.device.reset_fences(&[self.data.in_flight_fences[self.frame]])?;
+        let recording = Instant::now();
+        let (command_buffer, counts) = record_command_buffer(&self.device, &self.data, self.frame, image_index)?;
+        self.recording_stats.add(recording.elapsed());
+        self.stats.counts = counts;
 
-        
-        self.device.queue_submit(
+        let wait = &[(*self.data.image_available_semaphores[self.frame], vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)];
+        let signal_semaphores = &[*self.data.render_finished_semaphores[self.frame]];
+        self.data.frame_sync.submit(
+            &self.device,
             self.data.graphics_queue,
-            &[submit_info],
-            self.data.in_flight_fences[self.frame],
+            self.frame,
+            image_index,
+            &[command_buffer],
+            wait,
+            signal_semaphores,
         )?;
+        self.data.profiler.frame_submitted(self.frame);
+        self.data.statistics.frame_submitted(self.frame);
 
-        let swapchains = &[self.data.swapchain];
+        if self.data.settings.stats_in_title && self.title_updated.elapsed() >= TITLE_UPDATE_INTERVAL {
+            window.set_title(&format!("{} ({})", self.data.settings.title, self.stats));
+            self.title_updated = Instant::now();
+        }
+
+        let swapchains = &[*self.data.swapchain];
         let image_indices = &[image_index as u32];
         let present_info = vk::PresentInfoKHR::builder()
             .wait_semaphores(signal_semaphores)
@@ -165,119 +251,103 @@ impl App {
             return Err(anyhow!(e));
         }
     
-        self.frame = (self.frame + 1) % MAX_FRAMES_IN_FLIGHT;
+        self.frame = (self.frame + 1) % self.data.settings.frames_in_flight;
 
         Ok(())
     }
 
-    /// Destroys our Vulkan app.
-    pub unsafe fn destroy(&mut self) {
-        
-        self.device.device_wait_idle().unwrap();
+    /// Renders a single frame into the offscreen target and writes it to a PNG file.
+    pub unsafe fn render_headless(&mut self, path: &Path) -> Result<()> {
+        update_uniform_buffer(&self.camera, &self.data, 0)?;
 
-        self.destroy_swapchain();
-    
- 
+        let (command_buffer, counts) = record_command_buffer(&self.device, &self.data, self.frame, 0)?;
+        self.stats.counts = counts;
 
+        let value = self.data.frame_sync.submit(&self.device, self.data.graphics_queue, self.frame, 0, &[command_buffer], &[], &[])?;
+        self.data.frame_sync.wait(&self.device, value)?;
+        self.data.profiler.frame_submitted(self.frame);
+        self.data.profiler.read_frame(&self.device, self.frame)?;
+        self.data.statistics.frame_submitted(self.frame);
+        self.stats.pipeline = self.data.statistics.read_frame(&self.device, self.frame)?;
+        info!("Rendered {}.", self.stats);
 
+        save_offscreen_png(&self.instance, &self.device, &self.data, path)?;
 
-        self.data.in_flight_fences
-            .iter()
-            .for_each(|f| self.device.destroy_fence(*f, None));
+        info!("Wrote offscreen frame to `{}`.", path.display());
 
-        self.data.render_finished_semaphores
-            .iter()
-            .for_each(|s| self.device.destroy_semaphore(*s, None));
-        self.data.image_available_semaphores
-            .iter()
-            .for_each(|s| self.device.destroy_semaphore(*s, None));
+        Ok(())
+    }
 
+    /// Destroys our Vulkan app.
+    ///
+    /// Every handle is owned, so once the device is idle dropping the app
+    /// data destroys everything in dependency order, followed by the device
+    /// and the instance.
+    pub unsafe fn destroy(self) {
+        self.device.device_wait_idle().unwrap();
 
-        self.device.destroy_sampler(self.data.texture_sampler, None);
-        self.device.destroy_descriptor_set_layout(self.data.descriptor_set_layout, None);
-        
-        self.device.destroy_image(self.data.texture_image, None);
-        self.device.free_memory(self.data.texture_image_memory, None);
-        self.device.destroy_image_view(self.data.texture_image_view, None);
+        if let Err(e) = save_pipeline_cache(&self.device, &self.data) {
+            warn!("Failed to save the pipeline cache: {}", e);
+        }
 
-        
-        self.device.destroy_buffer(self.data.vertex_buffer, None);
-        self.device.free_memory(self.data.vertex_buffer_memory, None);
-        self.device.destroy_buffer(self.data.index_buffer, None);
-        self.device.free_memory(self.data.index_buffer_memory, None);
-        self.device.destroy_command_pool(self.data.command_pool, None);
-        self.device.destroy_command_pool(self.data.command_pool_transfer, None);
-        self.device.destroy_device(None);
-        self.instance.destroy_surface_khr(self.data.surface, None);
-      
-        if VALIDATION_ENABLED {
-            self.instance.destroy_debug_utils_messenger_ext(self.data.messenger, None);
+        if let Err(e) = self.data.profiler.report() {
+            warn!("Failed to save the GPU profile: {}", e);
         }
-        self.instance.destroy_instance(None);
-        
-        
+
+        let Self { device, data, .. } = self;
+        drop(data);
+
+        // Anything still alive here is held somewhere other than the app data.
+        device.tracker.borrow().report();
+
+        let stats = device.allocator.borrow().stats();
+        info!("Allocator: {} block(s), {} of {} bytes in use by {} allocation(s).", stats.block_count, stats.used_bytes, stats.reserved_bytes, stats.allocation_count);
     }
 
 
     unsafe fn recreate_swapchain(&mut self, window: &Window) -> Result<()> {
         self.device.device_wait_idle()?;
-        self.destroy_swapchain();
         create_swapchain(window, &self.instance, &self.device, &mut self.data)?;
         create_swapchain_image_views(&self.device, &mut self.data)?;
-        create_render_pass(&self.instance, &self.device, &mut self.data)?;
+        // The multisampled color attachment must match the new swapchain format.
+        create_render_graph(&self.instance, &mut self.data)?;
+        build_render_graph(&self.device, &mut self.data)?;
+        // Pipelines for the previous render passes are no longer needed.
+        self.data.pipelines.clear();
         create_pipeline(&self.device, &mut self.data)?;
-        create_depth_objects(&self.instance, &self.device, &mut self.data)?;
-        create_framebuffers(&self.device, &mut self.data)?;
         create_uniform_buffers(&self.instance, &self.device, &mut self.data)?;
         create_descriptor_pool(&self.device, &mut self.data)?;
         create_descriptor_sets(&self.device, &mut self.data)?;
-        create_command_buffers(&self.device, &mut self.data)?;
-        self.data.images_in_flight.resize(self.data.swapchain_images.len(), vk::Fence::null());
+        self.data.frame_sync.reset_images(self.data.swapchain_images.len());
         Ok(())
     }
-
-
-    unsafe fn destroy_swapchain(&mut self) {
-
-
-        self.device.destroy_image(self.data.depth_image, None);
-        self.device.free_memory(self.data.depth_image_memory, None);
-        self.device.destroy_image_view(self.data.depth_image_view,None);
-
-        self.device.free_command_buffers(self.data.command_pool, &self.data.command_buffers);
-        self.device.destroy_descriptor_pool(self.data.descriptor_pool, None);
-        self.data.uniform_buffers_memory.iter().for_each(|m| self.device.free_memory(*m, None));
-        self.data.uniform_buffers.iter().for_each(|b| self.device.destroy_buffer(*b, None));
-        self.data.framebuffers.iter().for_each(|f| self.device.destroy_framebuffer(*f, None));
-        self.device.destroy_pipeline(self.data.pipeline, None);
-        self.device.destroy_pipeline_layout(self.data.pipeline_layout, None);
-        self.device.destroy_render_pass(self.data.render_pass, None);
-        self.data.swapchain_image_views.iter().for_each(|v| self.device.destroy_image_view(*v, None));
-        self.device.destroy_swapchain_khr(self.data.swapchain, None);
-        
-    }
-    
-   
 }
 
 
 
 
 
-pub unsafe fn create_instance(window: &Window, entry: &Entry, data: &mut AppData) -> Result<Instance> {
+pub unsafe fn create_instance(window: Option<&Window>, entry: &Entry, data: &mut AppData) -> Result<SharedInstance> {
+    // The newest version the loader supports, up to the newest features the renderer knows of.
+    let loader_version = entry.version()?;
+    data.instance_version = Version::new(loader_version.major, loader_version.minor, 0).min(V1_3_0);
+
     let application_info = vk::ApplicationInfo::builder()
         .application_name(b"Vulkan Tutorial\0")
         .application_version(vk::make_version(1, 0, 0))
         .engine_name(b"No Engine\0")
         .engine_version(vk::make_version(1, 0, 0))
-        .api_version(vk::make_version(1, 0, 0));
+        .api_version(u32::from(data.instance_version));
 
-    let mut extensions = vk_window::get_required_instance_extensions(window)
+    // Headless rendering never creates a surface, so it needs no surface extensions.
+    let mut extensions = window
+        .map(|w| vk_window::get_required_instance_extensions(w))
+        .unwrap_or(&[])
         .iter()
         .map(|e| e.as_ptr())
         .collect::<Vec<_>>();
 
-    if VALIDATION_ENABLED {
+    if data.settings.validation {
         extensions.push(vk::EXT_DEBUG_UTILS_EXTENSION.name.as_ptr());
     }
     let available_layers = entry
@@ -286,11 +356,11 @@ pub unsafe fn create_instance(window: &Window, entry: &Entry, data: &mut AppData
         .map(|l| l.layer_name)
         .collect::<HashSet<_>>();
     
-    if VALIDATION_ENABLED && !available_layers.contains(&VALIDATION_LAYER) {
+    if data.settings.validation && !available_layers.contains(&VALIDATION_LAYER) {
         return Err(anyhow!("Validation layer requested but not supported."));
     }
     
-    let layers = if VALIDATION_ENABLED {
+    let layers = if data.settings.validation {
         vec![VALIDATION_LAYER.as_ptr()]
     } else {
         Vec::new()
@@ -320,19 +390,24 @@ pub unsafe fn create_instance(window: &Window, entry: &Entry, data: &mut AppData
         .message_type(vk::DebugUtilsMessageTypeFlagsEXT::all())
         .user_callback(Some(debug_callback));
 
-    if VALIDATION_ENABLED {
+    if data.settings.validation {
         info = info.push_next(&mut debug_info);
     }
 
-    let instance = entry.create_instance(&info, None)?;
+    let mut instance = OwnedInstance::new(entry.clone(), entry.create_instance(&info, None)?);
     
-    if VALIDATION_ENABLED {
+    if data.settings.validation {
     
-        data.messenger = instance.create_debug_utils_messenger_ext(&debug_info, None)?;
+        instance.messenger = instance.create_debug_utils_messenger_ext(&debug_info, None)?;
+    }
+
+    if let Some(window) = window {
+        instance.surface = vk_window::create_surface(&instance, &window, &window)?;
+        data.surface = instance.surface;
     }
  
 
-    Ok(instance)
+    Ok(Rc::new(instance))
 }
 
 
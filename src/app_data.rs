@@ -1,71 +1,91 @@
-use vulkanalia::vk;
+use vulkanalia::{vk, Version};
 
-use crate::mesh::Vertex;
+use crate::capabilities::DeviceCapabilities;
+use crate::owned::Owned;
+use crate::pipeline_desc::GraphicsPipelines;
+use crate::profiler::GpuProfiler;
+use crate::queue_family::QueueFamilyIndices;
+use crate::reflect::ShaderReflection;
+use crate::render_graph::{RenderBackend, RenderGraph};
+use crate::scene::Scene;
+use crate::settings::Settings;
+use crate::stats::StatisticsQueries;
+use crate::syncronization::FrameSync;
+use crate::upload::UploadContext;
 
 
 
 /// The Vulkan handles and associated properties used by our Vulkan app.
+///
+/// Handles are destroyed when the last [`Owned`] reference to them is
+/// dropped, so resetting this struct releases everything it created.
 #[derive(Clone, Debug, Default)]
 pub struct AppData {
-    pub messenger: vk::DebugUtilsMessengerEXT,
+    pub settings: Settings,
+    /// The Vulkan version the instance was created with.
+    pub instance_version: Version,
+
     pub physical_device: vk::PhysicalDevice,
+    /// The extensions and features enabled on the logical device.
+    pub capabilities: DeviceCapabilities,
     pub surface: vk::SurfaceKHR,
+    pub queue_families: QueueFamilyIndices,
     pub graphics_queue: vk::Queue,
     pub transfer_queue: vk::Queue,
     pub present_queue: vk::Queue,
 
     pub swapchain_format: vk::Format,
     pub swapchain_extent: vk::Extent2D,
-    pub swapchain: vk::SwapchainKHR,
+    pub swapchain: Owned<vk::SwapchainKHR>,
     pub swapchain_images: Vec<vk::Image>,
-    pub swapchain_image_views: Vec<vk::ImageView>,
-
-    pub render_pass: vk::RenderPass,
-    
-    pub descriptor_set_layout: vk::DescriptorSetLayout,
-    pub pipeline_layout: vk::PipelineLayout,
-    pub pipeline: vk::Pipeline,
+    pub swapchain_image_views: Vec<Owned<vk::ImageView>>,
 
-    pub framebuffers: Vec<vk::Framebuffer>,
+    pub render_backend: RenderBackend,
+    pub render_graph: RenderGraph,
     
-    pub command_pool: vk::CommandPool,
-    pub command_pool_transfer: vk::CommandPool,
+    /// Indexed by descriptor set number.
+    pub descriptor_set_layouts: Vec<Owned<vk::DescriptorSetLayout>>,
+    pub pipeline_layout: Owned<vk::PipelineLayout>,
+    pub pipeline: Owned<vk::Pipeline>,
+    /// Draws transparent submeshes with alpha blending and without writing depth.
+    pub transparent_pipeline: Owned<vk::Pipeline>,
+    pub pipeline_cache: Owned<vk::PipelineCache>,
+    pub pipelines: GraphicsPipelines,
+    /// Draws the scene as lines, only set when `FillModeNonSolid` is enabled.
+    pub wireframe: bool,
+
+    pub vert_shader: Vec<u8>,
+    pub frag_shader: Vec<u8>,
+    pub shader_reflection: ShaderReflection,
+
+    pub command_pool: Owned<vk::CommandPool>,
+    pub frame_command_pools: Vec<Owned<vk::CommandPool>>,
+    /// One per frame in flight, recorded every frame.
     pub command_buffers: Vec<vk::CommandBuffer>,
 
-    pub image_available_semaphores: Vec<vk::Semaphore>,
-    pub render_finished_semaphores: Vec<vk::Semaphore>,
+    pub image_available_semaphores: Vec<Owned<vk::Semaphore>>,
+    pub render_finished_semaphores: Vec<Owned<vk::Semaphore>>,
 
-    pub in_flight_fences: Vec<vk::Fence>,
-    pub images_in_flight: Vec<vk::Fence>,
+    pub frame_sync: FrameSync,
+    pub profiler: GpuProfiler,
+    pub statistics: StatisticsQueries,
 
 
-    pub vertices: Vec<Vertex>,
-    pub indices: Vec<u32>,
-    pub vertex_buffer: vk::Buffer,
-    pub vertex_buffer_memory: vk::DeviceMemory,
-    pub index_buffer: vk::Buffer,
-    pub index_buffer_memory: vk::DeviceMemory,
+    pub uploads: UploadContext,
+    pub scene: Scene,
 
-    pub uniform_buffers: Vec<vk::Buffer>,
-    pub uniform_buffers_memory: Vec<vk::DeviceMemory>,
-    pub descriptor_pool: vk::DescriptorPool,
+    pub uniform_buffers: Vec<Owned<vk::Buffer>>,
+    pub descriptor_pool: Owned<vk::DescriptorPool>,
     pub descriptor_sets: Vec<vk::DescriptorSet>,
     
 
-    pub mip_levels: u32,
-    pub texture_image: vk::Image,
-    pub texture_image_memory: vk::DeviceMemory,
-    pub texture_image_view: vk::ImageView,
-
-
-    pub depth_image: vk::Image,
-    pub depth_image_memory: vk::DeviceMemory,
-    pub depth_image_view: vk::ImageView,
-
-    pub texture_sampler: vk::Sampler,
+    pub msaa_samples : vk::SampleCountFlags,
+    /// Set when sample shading is requested and supported.
+    pub min_sample_shading: Option<f32>,
 
 
-    pub msaa_samples : vk::SampleCountFlags,
+    pub headless: bool,
+    pub offscreen_image: Owned<vk::Image>,
 }
 
 
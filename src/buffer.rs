@@ -1,19 +1,17 @@
-use vulkanalia::{vk::{self, DeviceV1_0,  HasBuilder, InstanceV1_0}, Device, Instance};
-use anyhow::{anyhow, Result};
+use vulkanalia::vk::{self, DeviceV1_0,  HasBuilder};
+use anyhow::Result;
 
 
-use crate::command::{begin_single_time_commands, end_single_time_commands};
 
-use crate::app_data::AppData;
+use crate::owned::{Owned, SharedDevice};
 
+#[track_caller]
 pub unsafe fn create_buffer(
-    instance: &Instance,
-    device: &Device,
-    data: &AppData,
+    device: &SharedDevice,
     size: vk::DeviceSize,
     usage: vk::BufferUsageFlags,
     properties: vk::MemoryPropertyFlags,
-) -> Result<(vk::Buffer, vk::DeviceMemory)> {
+) -> Result<Owned<vk::Buffer>> {
     let buffer_info = vk::BufferCreateInfo::builder()
         .size(size)
         .usage(usage)
@@ -23,70 +21,33 @@ pub unsafe fn create_buffer(
 
     let requirements = device.get_buffer_memory_requirements(buffer);
 
-    let memory_info = vk::MemoryAllocateInfo::builder()
-        .allocation_size(requirements.size)
-        .memory_type_index(get_memory_type_index(
-            instance,
-            data,
-            properties,
-            requirements,
-        )?);
+    // The buffer is only owned once memory has been allocated for it.
+    let buffer_memory = match device.allocator.borrow_mut().allocate(device, requirements, properties, true) {
+        Ok(memory) => memory,
+        Err(e) => {
+            device.destroy_buffer(buffer, None);
+            return Err(e);
+        }
+    };
+    let buffer = Owned::with_memory(device, buffer, buffer_memory);
 
-    let buffer_memory = device.allocate_memory(&memory_info, None)?;
+    device.bind_buffer_memory(*buffer, buffer_memory.memory, buffer_memory.offset)?;
 
-    device.bind_buffer_memory(buffer, buffer_memory, 0)?;
-
-    Ok((buffer, buffer_memory))
-}
-
-
-pub unsafe fn get_memory_type_index(
-    instance: &Instance,
-    data: &AppData,
-    properties: vk::MemoryPropertyFlags,
-    requirements: vk::MemoryRequirements,
-) -> Result<u32> {
-    let memory = instance.get_physical_device_memory_properties(data.physical_device);
-    (0..memory.memory_type_count)
-        .find(|i| {
-            let suitable = (requirements.memory_type_bits & (1 << i)) != 0;
-            let memory_type = memory.memory_types[*i as usize];
-            suitable && memory_type.property_flags.contains(properties)
-        })
-        .ok_or_else(|| anyhow!("Failed to find suitable memory type."))
-}
-
-pub unsafe fn copy_buffer(
-    device: &Device,
-    data: &AppData,
-    source: vk::Buffer,
-    destination: vk::Buffer,
-    size: vk::DeviceSize,
-) -> Result<()> {
-
-
-    let command_buffer = begin_single_time_commands(device, data)?;
-
-    let regions = vk::BufferCopy::builder().size(size);
-    device.cmd_copy_buffer(command_buffer, source, destination, &[regions]);
-
-    end_single_time_commands(device, data, command_buffer)?;
-
-    Ok(())
+    Ok(buffer)
 }
 
+#[track_caller]
 pub unsafe fn create_image(
-    instance: &Instance,
-    device: &Device,
-    data: &AppData,
+    device: &SharedDevice,
     width: u32,
     height: u32,
     mip_levels: u32,
+    samples: vk::SampleCountFlags,
     format: vk::Format,
     tiling: vk::ImageTiling,
     usage: vk::ImageUsageFlags,
     properties: vk::MemoryPropertyFlags,
-) -> Result<(vk::Image, vk::DeviceMemory)> {
+) -> Result<Owned<vk::Image>> {
     let info = vk::ImageCreateInfo::builder()
         .image_type(vk::ImageType::_2D)
         .extent(vk::Extent3D {
@@ -100,26 +61,25 @@ pub unsafe fn create_image(
         .tiling(tiling)
         .initial_layout(vk::ImageLayout::UNDEFINED)
         .usage(usage)
-        .samples(vk::SampleCountFlags::_1)
+        .samples(samples)
         .sharing_mode(vk::SharingMode::EXCLUSIVE);
 
     let image = device.create_image(&info, None)?;
 
     let requirements = device.get_image_memory_requirements(image);
 
-    let info = vk::MemoryAllocateInfo::builder()
-        .allocation_size(requirements.size)
-        .memory_type_index(get_memory_type_index(
-            instance,
-            data,
-            properties,
-            requirements,
-        )?);
+    let linear = tiling == vk::ImageTiling::LINEAR;
+    // The image is only owned once memory has been allocated for it.
+    let image_memory = match device.allocator.borrow_mut().allocate(device, requirements, properties, linear) {
+        Ok(memory) => memory,
+        Err(e) => {
+            device.destroy_image(image, None);
+            return Err(e);
+        }
+    };
+    let image = Owned::with_memory(device, image, image_memory);
 
-    let image_memory = device.allocate_memory(&info, None)?;
+    device.bind_image_memory(*image, image_memory.memory, image_memory.offset)?;
 
-    device.bind_image_memory(image, image_memory, 0)?;
-
-    Ok((image, image_memory))
+    Ok(image)
 }
-
@@ -0,0 +1,290 @@
+//! A camera that can fly freely or orbit a target, driven by keyboard and mouse input.
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use cgmath::{vec3, Deg, InnerSpace, Point3, Rad};
+use clap::ValueEnum;
+use serde::Deserialize;
+use winit::event::{ElementState, MouseButton, MouseScrollDelta, VirtualKeyCode, WindowEvent};
+
+use crate::mesh::{Mat4, Vec3};
+use crate::settings::Settings;
+
+
+/// Converts from OpenGL clip space, which `cgmath` targets, to Vulkan clip space.
+const CORRECTION: Mat4 = Mat4::new(
+    1.0,  0.0,       0.0, 0.0,
+    // We're also flipping the Y-axis with this line's `-1.0`.
+    0.0, -1.0,       0.0, 0.0,
+    0.0,  0.0, 1.0 / 2.0, 0.0,
+    0.0,  0.0, 1.0 / 2.0, 1.0,
+);
+
+/// The world is Z-up.
+const UP: Vec3 = vec3(0.0, 0.0, 1.0);
+
+/// Keeps the view direction from becoming parallel to [`UP`].
+const MAX_PITCH: Deg<f32> = Deg(89.0);
+
+/// The closest an orbiting camera can get to its target.
+const MIN_DISTANCE: f32 = 0.05;
+
+/// How far the camera rotates per pixel dragged.
+const ROTATE_SPEED: Deg<f32> = Deg(0.3);
+
+/// How far the distance to the target changes per line scrolled, as a fraction.
+const ZOOM_SPEED: f32 = 0.1;
+
+/// How far a flying camera moves per second, in multiples of its orbit distance.
+const FLY_SPEED: f32 = 1.0;
+
+/// How much faster a flying camera moves while shift is held.
+const FAST_MULTIPLIER: f32 = 4.0;
+
+/// The number of pixels treated as one line of scrolling.
+const PIXELS_PER_LINE: f32 = 20.0;
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Projection {
+    Perspective,
+    /// Shows the area at the orbit target that the perspective projection would.
+    Orthographic,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum CameraMode {
+    /// Rotating and zooming move the camera around a target.
+    Orbit,
+    /// Moving and looking around as in a first-person game.
+    Fly,
+}
+
+/// Where the camera is and how it projects the scene.
+///
+/// The camera looks along the direction given by its yaw and pitch. In orbit
+/// mode it looks at a target `distance` away in that direction.
+#[derive(Copy, Clone, Debug)]
+pub struct Camera {
+    pub eye: Point3<f32>,
+    /// The angle of the view direction around the Z axis, from the X axis.
+    pub yaw: Rad<f32>,
+    /// The angle of the view direction above the XY plane.
+    pub pitch: Rad<f32>,
+    /// The distance from the eye to the orbit target.
+    pub distance: f32,
+    pub mode: CameraMode,
+    pub projection: Projection,
+    /// The vertical field of view of the perspective projection.
+    pub fov: Deg<f32>,
+    pub near: f32,
+    pub far: f32,
+}
+
+impl Camera {
+    /// A camera at (2, 2, 2) looking at the origin, as configured by the settings.
+    pub fn new(settings: &Settings) -> Self {
+        let mut camera = Self {
+            eye: Point3::new(0.0, 0.0, 0.0),
+            yaw: Rad(0.0),
+            pitch: Rad(0.0),
+            distance: 1.0,
+            mode: settings.camera,
+            projection: settings.projection,
+            fov: Deg(settings.fov),
+            near: settings.near,
+            far: settings.far,
+        };
+
+        camera.look_at(Point3::new(2.0, 2.0, 2.0), Point3::new(0.0, 0.0, 0.0));
+        camera
+    }
+
+    /// Moves the camera to `eye` and points it at `target`, which becomes the orbit target.
+    pub fn look_at(&mut self, eye: Point3<f32>, target: Point3<f32>) {
+        let direction = target - eye;
+        self.eye = eye;
+        self.distance = direction.magnitude().max(MIN_DISTANCE);
+        self.yaw = Rad(direction.y.atan2(direction.x));
+        self.pitch = Rad((direction.z / self.distance).clamp(-1.0, 1.0).asin());
+        self.clamp_pitch();
+    }
+
+    /// The unit vector the camera looks along.
+    pub fn forward(&self) -> Vec3 {
+        let (yaw, pitch) = (self.yaw.0, self.pitch.0);
+        vec3(pitch.cos() * yaw.cos(), pitch.cos() * yaw.sin(), pitch.sin())
+    }
+
+    pub fn target(&self) -> Point3<f32> {
+        self.eye + self.forward() * self.distance
+    }
+
+    pub fn view(&self) -> Mat4 {
+        Mat4::look_to_rh(self.eye, self.forward(), UP)
+    }
+
+    /// The projection for a viewport with the given aspect ratio, in Vulkan clip space.
+    pub fn proj(&self, aspect: f32) -> Mat4 {
+        let proj = match self.projection {
+            Projection::Perspective => cgmath::perspective(self.fov, aspect, self.near, self.far),
+            Projection::Orthographic => {
+                let half_height = self.distance * (self.fov / 2.0).0.to_radians().tan();
+                let half_width = half_height * aspect;
+                cgmath::ortho(-half_width, half_width, -half_height, half_height, self.near, self.far)
+            }
+        };
+
+        CORRECTION * proj
+    }
+
+    /// Turns the view direction, around the eye when flying or around the target when orbiting.
+    pub fn rotate(&mut self, yaw: Rad<f32>, pitch: Rad<f32>) {
+        let target = self.target();
+        self.yaw += yaw;
+        self.pitch += pitch;
+        self.clamp_pitch();
+
+        if self.mode == CameraMode::Orbit {
+            self.eye = target - self.forward() * self.distance;
+        }
+    }
+
+    /// Moves the eye and the orbit target together, along camera-relative axes.
+    pub fn translate(&mut self, right: f32, up: f32, forward: f32) {
+        let forward_axis = self.forward();
+        let right_axis = forward_axis.cross(UP).normalize();
+        let up_axis = right_axis.cross(forward_axis);
+        self.eye += right_axis * right + up_axis * up + forward_axis * forward;
+    }
+
+    /// Moves towards the orbit target by a fraction of the distance to it.
+    pub fn zoom(&mut self, fraction: f32) {
+        let target = self.target();
+        self.distance = (self.distance * (1.0 - fraction)).max(MIN_DISTANCE);
+        self.eye = target - self.forward() * self.distance;
+    }
+
+    fn clamp_pitch(&mut self) {
+        let max = Rad::from(MAX_PITCH);
+        self.pitch = Rad(self.pitch.0.clamp(-max.0, max.0));
+    }
+}
+
+/// Turns window input into camera movement.
+///
+/// Orbit mode: drag with the left button to rotate around the target, drag
+/// with the right or middle button to pan and scroll to zoom.
+///
+/// Fly mode: move with WASD, descend and ascend with Q and E, hold shift to
+/// move faster, drag with the left button to look around and scroll to move
+/// forwards or backwards.
+///
+/// In either mode C switches between orbiting and flying and P switches between
+/// perspective and orthographic projection.
+#[derive(Clone, Debug, Default)]
+pub struct CameraController {
+    pressed_keys: HashSet<VirtualKeyCode>,
+    pressed_buttons: HashSet<MouseButton>,
+    cursor: Option<(f64, f64)>,
+}
+
+impl CameraController {
+    /// Applies an event to the camera, or records it to apply in [`CameraController::update`].
+    pub fn handle_event(&mut self, camera: &mut Camera, event: &WindowEvent) {
+        match event {
+            WindowEvent::KeyboardInput { input, .. } => {
+                let Some(key) = input.virtual_keycode else { return };
+                match input.state {
+                    ElementState::Pressed if self.pressed_keys.insert(key) => match key {
+                        VirtualKeyCode::C => camera.mode = match camera.mode {
+                            CameraMode::Orbit => CameraMode::Fly,
+                            CameraMode::Fly => CameraMode::Orbit,
+                        },
+                        VirtualKeyCode::P => camera.projection = match camera.projection {
+                            Projection::Perspective => Projection::Orthographic,
+                            Projection::Orthographic => Projection::Perspective,
+                        },
+                        _ => {}
+                    },
+                    ElementState::Pressed => {}
+                    ElementState::Released => {
+                        self.pressed_keys.remove(&key);
+                    }
+                }
+            }
+            WindowEvent::MouseInput { state, button, .. } => match state {
+                ElementState::Pressed => {
+                    self.pressed_buttons.insert(*button);
+                }
+                ElementState::Released => {
+                    self.pressed_buttons.remove(button);
+                }
+            },
+            WindowEvent::CursorMoved { position, .. } => {
+                let (x, y) = (position.x, position.y);
+                if let Some((last_x, last_y)) = self.cursor.replace((x, y)) {
+                    self.drag(camera, (x - last_x) as f32, (y - last_y) as f32);
+                }
+            }
+            WindowEvent::CursorLeft { .. } => self.cursor = None,
+            WindowEvent::MouseWheel { delta, .. } => {
+                let lines = match delta {
+                    MouseScrollDelta::LineDelta(_, y) => *y,
+                    MouseScrollDelta::PixelDelta(position) => position.y as f32 / PIXELS_PER_LINE,
+                };
+
+                match camera.mode {
+                    CameraMode::Orbit => camera.zoom(lines * ZOOM_SPEED),
+                    CameraMode::Fly => camera.translate(0.0, 0.0, lines * ZOOM_SPEED * camera.distance),
+                }
+            }
+            // Keys released while the window is unfocused are never reported.
+            WindowEvent::Focused(false) => {
+                self.pressed_keys.clear();
+                self.pressed_buttons.clear();
+            }
+            _ => {}
+        }
+    }
+
+    /// Moves a flying camera by the keys held down over the time since the last frame.
+    pub fn update(&self, camera: &mut Camera, elapsed: Duration) {
+        if camera.mode != CameraMode::Fly {
+            return;
+        }
+
+        let axis = |positive, negative| {
+            let pressed = |key| self.pressed_keys.contains(&key) as i32 as f32;
+            pressed(positive) - pressed(negative)
+        };
+
+        let fast = self.pressed_keys.contains(&VirtualKeyCode::LShift)
+            || self.pressed_keys.contains(&VirtualKeyCode::RShift);
+        let speed = FLY_SPEED * camera.distance * if fast { FAST_MULTIPLIER } else { 1.0 };
+        let step = speed * elapsed.as_secs_f32();
+
+        camera.translate(
+            axis(VirtualKeyCode::D, VirtualKeyCode::A) * step,
+            axis(VirtualKeyCode::E, VirtualKeyCode::Q) * step,
+            axis(VirtualKeyCode::W, VirtualKeyCode::S) * step,
+        );
+    }
+
+    fn drag(&self, camera: &mut Camera, dx: f32, dy: f32) {
+        if self.pressed_buttons.contains(&MouseButton::Left) {
+            // Dragging right or up turns the view right or up.
+            let speed = Rad::from(ROTATE_SPEED);
+            camera.rotate(speed * -dx, speed * -dy);
+        } else if camera.mode == CameraMode::Orbit
+            && (self.pressed_buttons.contains(&MouseButton::Right) || self.pressed_buttons.contains(&MouseButton::Middle))
+        {
+            // Pan so the point under the cursor roughly follows it.
+            let scale = camera.distance * 0.002;
+            camera.translate(-dx * scale, dy * scale, 0.0);
+        }
+    }
+}
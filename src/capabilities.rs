@@ -0,0 +1,241 @@
+//! Negotiates the device extensions and features the renderer enables.
+//!
+//! Subsystems declare what they need in a [`DeviceRequirements`]. Physical
+//! devices without something required are rejected, and optional extensions
+//! and features are enabled wherever they are supported. The outcome is kept
+//! in a [`DeviceCapabilities`] so code paths can check what was enabled.
+
+use std::collections::HashSet;
+
+use anyhow::{anyhow, Result};
+use vulkanalia::prelude::v1_0::*;
+use vulkanalia::vk::InstanceV1_1;
+use vulkanalia::Version;
+
+use crate::app_data::AppData;
+
+
+/// The version for Vulkan `1.3.0`, which `vulkanalia` has no constant for.
+pub const V1_3_0: Version = Version::new(1, 3, 0);
+
+/// The Vulkan feature structs, queried and enabled together.
+///
+/// The `next` pointers are only set while the structs are chained for a call.
+#[derive(Copy, Clone, Debug, Default)]
+struct FeatureStructs {
+    v10: vk::PhysicalDeviceFeatures,
+    /// Only exists since Vulkan 1.2, so its features need Vulkan 1.2.
+    v11: vk::PhysicalDeviceVulkan11Features,
+    v12: vk::PhysicalDeviceVulkan12Features,
+    v13: vk::PhysicalDeviceVulkan13Features,
+}
+
+macro_rules! features {
+    ($($feature:ident => $structs:ident.$field:ident since $version:expr),* $(,)?) => {
+        /// A device feature, named after its field in the Vulkan feature structs.
+        #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+        pub enum Feature {
+            $($feature),*
+        }
+
+        impl Feature {
+            /// The device API version needed to query or enable the feature.
+            fn version(self) -> Version {
+                match self {
+                    $(Self::$feature => $version),*
+                }
+            }
+
+            fn get(self, structs: &FeatureStructs) -> bool {
+                match self {
+                    $(Self::$feature => structs.$structs.$field == vk::TRUE),*
+                }
+            }
+
+            fn set(self, structs: &mut FeatureStructs) {
+                match self {
+                    $(Self::$feature => structs.$structs.$field = vk::TRUE),*
+                }
+            }
+        }
+    };
+}
+
+features! {
+    SamplerAnisotropy => v10.sampler_anisotropy since Version::V1_0_0,
+    SampleRateShading => v10.sample_rate_shading since Version::V1_0_0,
+    FillModeNonSolid => v10.fill_mode_non_solid since Version::V1_0_0,
+    PipelineStatisticsQuery => v10.pipeline_statistics_query since Version::V1_0_0,
+    TimelineSemaphore => v12.timeline_semaphore since Version::V1_2_0,
+    HostQueryReset => v12.host_query_reset since Version::V1_2_0,
+    DynamicRendering => v13.dynamic_rendering since V1_3_0,
+    Synchronization2 => v13.synchronization2 since V1_3_0,
+}
+
+/// The extensions and features subsystems need, or can make use of.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceRequirements {
+    required_extensions: Vec<vk::ExtensionName>,
+    optional_extensions: Vec<vk::ExtensionName>,
+    required_features: Vec<Feature>,
+    optional_features: Vec<Feature>,
+}
+
+impl DeviceRequirements {
+    pub fn require_extension(&mut self, extension: vk::ExtensionName) -> &mut Self {
+        self.required_extensions.push(extension);
+        self
+    }
+
+    /// Enables an extension if the device supports it.
+    pub fn request_extension(&mut self, extension: vk::ExtensionName) -> &mut Self {
+        self.optional_extensions.push(extension);
+        self
+    }
+
+    pub fn require_feature(&mut self, feature: Feature) -> &mut Self {
+        self.required_features.push(feature);
+        self
+    }
+
+    /// Enables a feature if the device supports it.
+    pub fn request_feature(&mut self, feature: Feature) -> &mut Self {
+        self.optional_features.push(feature);
+        self
+    }
+
+    /// Decides what to enable on a physical device, failing if it lacks anything required.
+    pub unsafe fn negotiate(
+        &self,
+        instance: &Instance,
+        data: &AppData,
+        physical_device: vk::PhysicalDevice,
+    ) -> Result<DeviceCapabilities> {
+        let properties = instance.get_physical_device_properties(physical_device);
+
+        // Features of newer versions can't be used through an older instance.
+        let api_version = Version::from(properties.api_version).min(data.instance_version);
+        let api_version = Version::new(api_version.major, api_version.minor, 0);
+
+        let supported_extensions = instance
+            .enumerate_device_extension_properties(physical_device, None)?
+            .iter()
+            .map(|e| e.extension_name)
+            .collect::<HashSet<_>>();
+
+        let supported_features = get_features(instance, data, physical_device, api_version);
+        let supports = |f: Feature| f.version() <= api_version && f.get(&supported_features);
+
+        if let Some(extension) = self.required_extensions.iter().find(|e| !supported_extensions.contains(e)) {
+            return Err(anyhow!("Missing required device extension `{}`.", extension));
+        }
+
+        if let Some(feature) = self.required_features.iter().find(|f| !supports(**f)) {
+            return Err(anyhow!("Missing required device feature {:?} (Vulkan {}).", feature, feature.version()));
+        }
+
+        let extensions = self.required_extensions
+            .iter()
+            .chain(self.optional_extensions.iter().filter(|e| supported_extensions.contains(e)))
+            .cloned()
+            .collect();
+
+        let features = self.required_features
+            .iter()
+            .chain(self.optional_features.iter().filter(|f| supports(**f)))
+            .cloned()
+            .collect();
+
+        let optional_supported = self.optional_extensions.iter().filter(|e| supported_extensions.contains(e)).count()
+            + self.optional_features.iter().filter(|f| supports(**f)).count();
+
+        Ok(DeviceCapabilities { api_version, extensions, features, optional_supported })
+    }
+}
+
+/// What was enabled on the logical device.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceCapabilities {
+    /// The highest Vulkan version both the instance and the device support.
+    pub api_version: Version,
+    extensions: HashSet<vk::ExtensionName>,
+    features: HashSet<Feature>,
+    /// How many of the optional extensions and features were supported, for ranking devices.
+    pub optional_supported: usize,
+}
+
+impl DeviceCapabilities {
+    pub fn has_extension(&self, extension: vk::ExtensionName) -> bool {
+        self.extensions.contains(&extension)
+    }
+
+    pub fn has_feature(&self, feature: Feature) -> bool {
+        self.features.contains(&feature)
+    }
+
+    /// The extensions to enable, as C strings.
+    pub fn extension_names(&self) -> Vec<*const i8> {
+        self.extensions.iter().map(|e| e.as_ptr()).collect()
+    }
+
+    /// Calls `f` with the feature structs to chain onto a `VkDeviceCreateInfo`.
+    ///
+    /// Devices older than Vulkan 1.1 take the 1.0 features through
+    /// `pEnabledFeatures` instead, so `f` is given those separately.
+    pub fn with_enabled_features<T>(
+        &self,
+        f: impl FnOnce(&vk::PhysicalDeviceFeatures, Option<&mut vk::PhysicalDeviceFeatures2>) -> T,
+    ) -> T {
+        let mut structs = FeatureStructs::default();
+        self.features.iter().for_each(|f| f.set(&mut structs));
+
+        if self.api_version < Version::V1_1_0 {
+            return f(&structs.v10, None);
+        }
+
+        let FeatureStructs { v10, mut v11, mut v12, mut v13 } = structs;
+        let mut features = vk::PhysicalDeviceFeatures2::builder().features(v10);
+        if self.api_version >= Version::V1_2_0 {
+            features = features.push_next(&mut v11).push_next(&mut v12);
+        }
+        if self.api_version >= V1_3_0 {
+            features = features.push_next(&mut v13);
+        }
+
+        f(&v10, Some(&mut *features))
+    }
+}
+
+/// Queries every feature struct the API version has.
+unsafe fn get_features(
+    instance: &Instance,
+    data: &AppData,
+    physical_device: vk::PhysicalDevice,
+    api_version: Version,
+) -> FeatureStructs {
+    if api_version < Version::V1_1_0 || data.instance_version < Version::V1_1_0 {
+        return FeatureStructs { v10: instance.get_physical_device_features(physical_device), ..Default::default() };
+    }
+
+    let mut v11 = vk::PhysicalDeviceVulkan11Features::default();
+    let mut v12 = vk::PhysicalDeviceVulkan12Features::default();
+    let mut v13 = vk::PhysicalDeviceVulkan13Features::default();
+
+    let mut features = vk::PhysicalDeviceFeatures2::builder();
+    if api_version >= Version::V1_2_0 {
+        features = features.push_next(&mut v11).push_next(&mut v12);
+    }
+    if api_version >= V1_3_0 {
+        features = features.push_next(&mut v13);
+    }
+
+    instance.get_physical_device_features2(physical_device, &mut features);
+    let v10 = features.features;
+
+    // The structs no longer point at each other once copied out.
+    v11.next = std::ptr::null_mut();
+    v12.next = std::ptr::null_mut();
+    v13.next = std::ptr::null_mut();
+
+    FeatureStructs { v10, v11, v12, v13 }
+}
@@ -1,41 +1,124 @@
 
 use crate::app_data::AppData;
 use crate::image::get_depth_format;
+use crate::owned::{Owned, SharedDevice};
+use crate::profiler::FRAME_SCOPE;
 use crate::queue_family::QueueFamilyIndices;
+use crate::render_graph::{Backbuffer, LoadOp, Pass, RenderGraph, BACKBUFFER};
+use crate::scene::PushConstants;
+use crate::stats::DrawCounts;
+use crate::shader::{FRAME_SET, MATERIAL_SET};
 
 
-use anyhow:: Result;
+use std::mem::size_of;
+use std::slice;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use log::*;
 use vulkanalia::prelude::v1_0::*;
 
 
 
-pub unsafe fn create_framebuffers(device: &Device, data: &mut AppData) -> Result<()> {
-    data.framebuffers = data
-        .swapchain_image_views
-        .iter()
-        .map(|i| {
-            let attachments = &[*i,
-                data.depth_image_view
-                
-                ];
-            let create_info = vk::FramebufferCreateInfo::builder()
-                .render_pass(data.render_pass)
-                .attachments(attachments)
-                .width(data.swapchain_extent.width)
-                .height(data.swapchain_extent.height)
-                .layers(1);
-
-            device.create_framebuffer(&create_info, None)
-        })
-        .collect::<Result<Vec<_>, _>>()?;
+/// The name of the pass that draws the model.
+pub const SCENE_PASS: &str = "scene";
+
+/// How often the average command recording time is logged.
+const RECORDING_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+
+/// Accumulates the CPU time spent recording command buffers.
+#[derive(Clone, Debug)]
+pub struct RecordingStats {
+    total: Duration,
+    frames: u32,
+    since: Instant,
+}
+
+impl Default for RecordingStats {
+    fn default() -> Self {
+        Self { total: Duration::ZERO, frames: 0, since: Instant::now() }
+    }
+}
+
+impl RecordingStats {
+    /// Adds the recording time of a frame, logging the average every few seconds.
+    pub fn add(&mut self, elapsed: Duration) {
+        self.total += elapsed;
+        self.frames += 1;
+
+        if self.since.elapsed() >= RECORDING_REPORT_INTERVAL {
+            let average = self.total.as_secs_f64() * 1000.0 / self.frames as f64;
+            debug!("Recorded {} frame(s) in {:.3} ms on average.", self.frames, average);
+            *self = Self::default();
+        }
+    }
+}
+
+
+/// Describes the passes that make up a frame.
+///
+/// With MSAA the scene is drawn into multisampled color and depth attachments
+/// and resolved into the backbuffer.
+pub unsafe fn create_render_graph(instance: &Instance, data: &mut AppData) -> Result<()> {
+    let mut graph = RenderGraph::new(data.render_backend);
+    let samples = data.msaa_samples;
+    let depth = graph.add_attachment("depth", get_depth_format(instance, data)?, samples);
+
+    let color_clear_value = vk::ClearValue {
+        color: vk::ClearColorValue {
+            float32: data.settings.clear_color,
+        },
+    };
+
+    let depth_clear_value = vk::ClearValue {
+        depth_stencil: vk::ClearDepthStencilValue {
+            depth: 1.0,
+            stencil: 0,
+        },
+    };
+
+    let scene_pass = if samples == vk::SampleCountFlags::_1 {
+        Pass::new(SCENE_PASS, record_scene_pass)
+            .color(BACKBUFFER, LoadOp::Clear(color_clear_value))
+    } else {
+        let color = graph.add_attachment("color", data.swapchain_format, samples);
+        Pass::new(SCENE_PASS, record_scene_pass)
+            .color(color, LoadOp::Clear(color_clear_value))
+            .resolve(BACKBUFFER)
+    };
+
+    graph.add_pass(scene_pass.depth(depth, LoadOp::Clear(depth_clear_value)));
+
+    data.render_graph = graph;
 
     Ok(())
 }
 
+/// Creates the render passes, framebuffers and attachments for the current swapchain.
+pub unsafe fn build_render_graph(device: &SharedDevice, data: &mut AppData) -> Result<()> {
+    // Headless frames are copied out to host memory instead of presented.
+    let final_layout = if data.headless {
+        vk::ImageLayout::TRANSFER_SRC_OPTIMAL
+    } else {
+        vk::ImageLayout::PRESENT_SRC_KHR
+    };
+
+    let backbuffer = Backbuffer {
+        format: data.swapchain_format,
+        extent: data.swapchain_extent,
+        images: &data.swapchain_images,
+        views: &data.swapchain_image_views,
+        final_layout,
+    };
+
+    data.render_graph.build(device, &backbuffer)
+}
+
 
 pub unsafe fn create_command_pools(
     instance: &Instance,
-    device: &Device,
+    device: &SharedDevice,
     data: &mut AppData,
 ) -> Result<()> {
 
@@ -45,161 +128,146 @@ pub unsafe fn create_command_pools(
         .flags(vk::CommandPoolCreateFlags::empty()) // Optional.
         .queue_family_index(indices.graphics);
 
-    data.command_pool = device.create_command_pool(&graphics_info, None)?;
-
+    data.command_pool = Owned::new(device, device.create_command_pool(&graphics_info, None)?);
 
 
+    // Each frame in flight records into its own pool, which is reset as a
+    // whole once the frame's previous submission has completed.
 
-    let transfer_info = vk::CommandPoolCreateInfo::builder()
-        .flags(vk::CommandPoolCreateFlags::empty()) // Optional.
-        .queue_family_index(indices.transfer);
+    let frame_info = vk::CommandPoolCreateInfo::builder()
+        .flags(vk::CommandPoolCreateFlags::TRANSIENT)
+        .queue_family_index(indices.graphics);
 
-    data.command_pool_transfer = device.create_command_pool(&transfer_info, None)?;
+    data.frame_command_pools = (0..data.settings.frames_in_flight)
+        .map(|_| Ok(Owned::new(device, device.create_command_pool(&frame_info, None)?)))
+        .collect::<Result<Vec<_>>>()?;
 
 
     Ok(())
 }
 
+/// Allocates one command buffer per frame in flight from that frame's pool.
 pub unsafe fn create_command_buffers(device: &Device, data: &mut AppData) -> Result<()> {
-    let allocate_info = vk::CommandBufferAllocateInfo::builder()
-        .command_pool(data.command_pool)
-        .level(vk::CommandBufferLevel::PRIMARY)
-        .command_buffer_count(data.framebuffers.len() as u32);
-
-   
-    data.command_buffers = device.allocate_command_buffers(&allocate_info)?;
-  
-    
-    for (i, command_buffer) in data.command_buffers.iter().enumerate() {
-        let info = vk::CommandBufferBeginInfo::builder();
-
-        device.begin_command_buffer(*command_buffer, &info)?;
-
-        let render_area = vk::Rect2D::builder()
-            .offset(vk::Offset2D::default())
-            .extent(data.swapchain_extent);
-
-        let color_clear_value = vk::ClearValue {
-            color: vk::ClearColorValue {
-                float32: [0.0, 0.0, 0.0, 1.0],
-            },
-        };
-        
-        let depth_clear_value = vk::ClearValue {
-            depth_stencil: vk::ClearDepthStencilValue {
-                depth: 1.0,
-                stencil: 0,
-            },
-        };
-
-        let clear_values = &[color_clear_value,
-                depth_clear_value
-            ];
-        let info = vk::RenderPassBeginInfo::builder()
-            .render_pass(data.render_pass)
-            .framebuffer(data.framebuffers[i])
-            .render_area(render_area)
-            .clear_values(clear_values);
-
-        device.cmd_begin_render_pass(*command_buffer, &info, vk::SubpassContents::INLINE);
-        device.cmd_bind_pipeline(*command_buffer, vk::PipelineBindPoint::GRAPHICS, data.pipeline);
-        device.cmd_bind_vertex_buffers(*command_buffer, 0, &[data.vertex_buffer], &[0]);
-        device.cmd_bind_index_buffer(*command_buffer, data.index_buffer, 0, vk::IndexType::UINT32);
-        device.cmd_bind_descriptor_sets(
-            *command_buffer,
-            vk::PipelineBindPoint::GRAPHICS,
-            data.pipeline_layout,
-            0,
-            &[data.descriptor_sets[i]],
-            &[],
-        );
-        
-        device.cmd_draw_indexed(*command_buffer, data.indices.len() as u32, 1, 0, 0, 0);
-        device.cmd_end_render_pass(*command_buffer);
-
-        device.end_command_buffer(*command_buffer)?;
-    }
-
-
+    data.command_buffers = data.frame_command_pools
+        .iter()
+        .map(|pool| {
+            let allocate_info = vk::CommandBufferAllocateInfo::builder()
+                .command_pool(**pool)
+                .level(vk::CommandBufferLevel::PRIMARY)
+                .command_buffer_count(1);
 
+            Ok(device.allocate_command_buffers(&allocate_info)?[0])
+        })
+        .collect::<Result<Vec<_>>>()?;
 
-    
     Ok(())
 }
 
-
-
-
-
-pub unsafe fn create_render_pass(
-    instance: &Instance,
+/// Records the command buffer of a frame in flight for a swapchain image,
+/// returning it with the draws and binds it records.
+///
+/// The frame's previous submission must have completed.
+pub unsafe fn record_command_buffer(
     device: &Device,
-    data: &mut AppData,
-) -> Result<()> {
-    let color_attachment = vk::AttachmentDescription::builder()
-        .format(data.swapchain_format)
-        .samples(vk::SampleCountFlags::_1)
-        .load_op(vk::AttachmentLoadOp::CLEAR)
-        .store_op(vk::AttachmentStoreOp::STORE)
-        .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
-        .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
-        .initial_layout(vk::ImageLayout::UNDEFINED)
-        .final_layout(vk::ImageLayout::PRESENT_SRC_KHR);
-
-
-
-    let color_attachment_ref = vk::AttachmentReference::builder()
-        .attachment(0)
-        .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
-
-
-    let color_attachments = &[color_attachment_ref];
-
-
-    let depth_stencil_attachment = vk::AttachmentDescription::builder()
-        .format(get_depth_format(instance, data)?)
-        .samples(vk::SampleCountFlags::_1)
-        .load_op(vk::AttachmentLoadOp::CLEAR)
-        .store_op(vk::AttachmentStoreOp::DONT_CARE)
-        .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
-        .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
-        .initial_layout(vk::ImageLayout::UNDEFINED)
-        .final_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
-
-    let depth_stencil_attachment_ref = vk::AttachmentReference::builder()
-        .attachment(1)
-        .layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
-
-
+    data: &AppData,
+    frame: usize,
+    image_index: usize,
+) -> Result<(vk::CommandBuffer, DrawCounts)> {
+    device.reset_command_pool(*data.frame_command_pools[frame], vk::CommandPoolResetFlags::empty())?;
 
+    let command_buffer = data.command_buffers[frame];
+    let info = vk::CommandBufferBeginInfo::builder()
+        .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
 
+    device.begin_command_buffer(command_buffer, &info)?;
+    data.profiler.begin_frame(device, command_buffer, frame);
+    data.statistics.begin(device, command_buffer, frame);
 
-    let subpass = vk::SubpassDescription::builder()
-        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
-        .color_attachments(color_attachments)
-        .depth_stencil_attachment(&depth_stencil_attachment_ref);
+    let mut counts = DrawCounts::default();
+    data.render_graph.record(device, data, command_buffer, frame, image_index, &mut counts)?;
 
+    data.statistics.end(device, command_buffer, frame);
+    data.profiler.end_scope(device, command_buffer, frame, FRAME_SCOPE);
+    device.end_command_buffer(command_buffer)?;
 
+    Ok((command_buffer, counts))
+}
 
-    let dependency = vk::SubpassDependency::builder()
-        .src_subpass(vk::SUBPASS_EXTERNAL)
-        .dst_subpass(0)
-        .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT  | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS)
-        .src_access_mask(vk::AccessFlags::empty())
-        .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS)
-        .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE);
-        
-    let attachments = &[color_attachment, 
-        depth_stencil_attachment
-        ];
-    let subpasses = &[subpass];
-    let dependencies = &[dependency];
-    let info = vk::RenderPassCreateInfo::builder()
-        .attachments(attachments)
-        .subpasses(subpasses)
-        .dependencies(dependencies);
-    
-    data.render_pass = device.create_render_pass(&info, None)?;
+unsafe fn record_scene_pass(
+    device: &Device,
+    data: &AppData,
+    command_buffer: vk::CommandBuffer,
+    image_index: usize,
+    counts: &mut DrawCounts,
+) -> Result<()> {
+    device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, *data.pipeline);
+
+    let viewport = vk::Viewport::builder()
+        .width(data.swapchain_extent.width as f32)
+        .height(data.swapchain_extent.height as f32)
+        .max_depth(1.0);
+    let scissor = vk::Rect2D::builder().extent(data.swapchain_extent);
+    device.cmd_set_viewport(command_buffer, 0, &[viewport]);
+    device.cmd_set_scissor(command_buffer, 0, &[scissor]);
+    device.cmd_bind_descriptor_sets(
+        command_buffer,
+        vk::PipelineBindPoint::GRAPHICS,
+        *data.pipeline_layout,
+        FRAME_SET,
+        &[data.descriptor_sets[image_index]],
+        &[],
+    );
+    counts.bind_descriptor_sets();
+
+    let push_constant_stages = data.shader_reflection.push_constant_ranges
+        .iter()
+        .fold(vk::ShaderStageFlags::empty(), |stages, r| stages | r.stage_flags);
+
+    // Transparent submeshes are blended over every opaque one, in scene order.
+    let scene = &data.scene;
+    for transparent in [false, true] {
+        if transparent {
+            device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, *data.transparent_pipeline);
+        }
+
+        for instance in &scene.instances {
+            let mesh = &scene.meshes[instance.mesh];
+            if !mesh.submeshes.iter().any(|s| s.transparent == transparent) {
+                continue;
+            }
+
+            device.cmd_bind_vertex_buffers(command_buffer, 0, &[*mesh.vertex_buffer], &[0]);
+            device.cmd_bind_index_buffer(command_buffer, *mesh.index_buffer, 0, vk::IndexType::UINT32);
+
+            for submesh in mesh.submeshes.iter().filter(|s| s.transparent == transparent) {
+                // Textures have no descriptor set if the shaders declare no material bindings.
+                let descriptor_set = scene.textures[submesh.texture].descriptor_set;
+                if !descriptor_set.is_null() {
+                    device.cmd_bind_descriptor_sets(
+                        command_buffer,
+                        vk::PipelineBindPoint::GRAPHICS,
+                        *data.pipeline_layout,
+                        MATERIAL_SET,
+                        &[descriptor_set],
+                        &[],
+                    );
+                    counts.bind_descriptor_sets();
+                }
+
+                let push_constants = PushConstants { model: instance.transform, base_color: submesh.base_color };
+                device.cmd_push_constants(
+                    command_buffer,
+                    *data.pipeline_layout,
+                    push_constant_stages,
+                    0,
+                    slice::from_raw_parts(&push_constants as *const PushConstants as *const u8, size_of::<PushConstants>()),
+                );
+
+                device.cmd_draw_indexed(command_buffer, submesh.index_count, 1, submesh.first_index, 0, 0);
+                counts.draw_indexed(submesh.index_count, 1);
+            }
+        }
+    }
 
     Ok(())
 }
@@ -211,7 +279,7 @@ pub unsafe fn begin_single_time_commands(
 ) -> Result<vk::CommandBuffer> {
     let info = vk::CommandBufferAllocateInfo::builder()
         .level(vk::CommandBufferLevel::PRIMARY)
-        .command_pool(data.command_pool)
+        .command_pool(*data.command_pool)
         .command_buffer_count(1);
 
     let command_buffer = device.allocate_command_buffers(&info)?[0];
@@ -238,7 +306,7 @@ pub unsafe fn end_single_time_commands(
     device.queue_submit(data.graphics_queue, &[info], vk::Fence::null())?;
     device.queue_wait_idle(data.graphics_queue)?;
 
-    device.free_command_buffers(data.command_pool, &[command_buffer]);
+    device.free_command_buffers(*data.command_pool, &[command_buffer]);
 
     Ok(())
-}
\ No newline at end of file
+}
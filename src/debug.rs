@@ -1,5 +1,5 @@
 use vulkanalia::vk;
-use std::{ffi::CStr, os::raw::c_void};
+use std::{collections::HashMap, ffi::CStr, os::raw::c_void, panic::Location};
 use log::*;
 
 
@@ -33,3 +33,46 @@ pub extern "system" fn debug_callback(
     vk::FALSE
 }
 
+
+
+/// Records where each live handle was created so leaks can be traced back to their source.
+///
+/// Only populated when enabled, which it is when validation is.
+#[derive(Clone, Debug, Default)]
+pub struct HandleTracker {
+    enabled: bool,
+    live: HashMap<(vk::ObjectType, u64), &'static Location<'static>>,
+}
+
+impl HandleTracker {
+    pub fn new(enabled: bool) -> Self {
+        Self { enabled, live: HashMap::new() }
+    }
+
+    pub fn insert(&mut self, type_: vk::ObjectType, handle: u64, location: &'static Location<'static>) {
+        if self.enabled {
+            self.live.insert((type_, handle), location);
+        }
+    }
+
+    pub fn remove(&mut self, type_: vk::ObjectType, handle: u64) {
+        if self.enabled {
+            self.live.remove(&(type_, handle));
+        }
+    }
+
+    /// Logs every handle that is still alive along with where it was created.
+    pub fn report(&self) {
+        if self.live.is_empty() {
+            return;
+        }
+
+        let mut live = self.live.iter().collect::<Vec<_>>();
+        live.sort_by_key(|(_, l)| (l.file(), l.line()));
+
+        error!("{} Vulkan handle(s) still alive:", live.len());
+        for ((type_, handle), location) in live {
+            error!("  {:?} {:#x} created at {}", type_, handle, location);
+        }
+    }
+}
@@ -1,105 +1,295 @@
 
-use crate::msaa::get_max_msaa_samples;
+use crate::allocator::Allocator;
+use crate::capabilities::{DeviceCapabilities, DeviceRequirements};
+use crate::msaa::{get_min_sample_shading, get_msaa_samples};
+use crate::render_graph::{self, RenderBackend};
+use crate::{image, msaa, pipeline, stats, swapchain, syncronization};
+use crate::owned::{OwnedDevice, SharedDevice, SharedInstance};
 use crate::SuitabilityError;
 
 use crate::app_data::AppData;
 
 use crate::swapchain::SwapchainSupport;
-use crate::PORTABILITY_MACOS_VERSION;
-use crate::debug::{VALIDATION_ENABLED,VALIDATION_LAYER};
+use crate::debug::{HandleTracker, VALIDATION_LAYER};
 
 use anyhow::{anyhow, Result};
 use log::*;
 use vulkanalia::vk::{DeviceV1_0, HasBuilder, InstanceV1_0};
-use vulkanalia::{vk, Instance,Entry,Device};
+use vulkanalia::{vk, Instance, Version};
+use vulkanalia::vk::InstanceV1_1;
 
 use crate::queue_family::QueueFamilyIndices;
 
+use std::cmp::Reverse;
 use std::collections::HashSet;
+use std::rc::Rc;
 
 
-const DEVICE_EXTENSIONS: &[vk::ExtensionName] = &[vk::KHR_SWAPCHAIN_EXTENSION.name];
-
-pub unsafe fn pick_physical_device(instance: &Instance, data: &mut AppData) -> Result<()> {
-    for physical_device in instance.enumerate_physical_devices()? {
-        let properties = instance.get_physical_device_properties(physical_device);
+/// How the user chose a physical device, with `--device` or `VULKAN_DEVICE`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum DeviceSelector {
+    /// The position of the device in the list of physical devices.
+    Index(usize),
+    Uuid([u8; vk::UUID_SIZE]),
+    /// A case-insensitive part of the device name.
+    Name(String),
+}
 
-        if let Err(error) = check_physical_device(instance, data, physical_device) {
-            warn!("Skipping physical device (`{}`): {}", properties.device_name, error);
+impl DeviceSelector {
+    fn parse(value: &str) -> Self {
+        let hex = value.replace('-', "");
+        if let Ok(index) = value.parse() {
+            Self::Index(index)
+        } else if hex.len() == 2 * vk::UUID_SIZE && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            let mut uuid = [0; vk::UUID_SIZE];
+            for (i, byte) in uuid.iter_mut().enumerate() {
+                *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap();
+            }
+            Self::Uuid(uuid)
         } else {
-            info!("Selected physical device (`{}`).", properties.device_name);
-            data.physical_device = physical_device;
-            data.msaa_samples = get_max_msaa_samples(instance, data);
-            return Ok(());
+            Self::Name(value.to_lowercase())
         }
     }
 
-    Err(anyhow!("Failed to find suitable physical device."))
+    fn matches(&self, device: &DeviceInfo) -> bool {
+        match self {
+            Self::Index(index) => device.index == *index,
+            Self::Uuid(uuid) => device.uuid == Some(*uuid),
+            Self::Name(name) => device.name.to_lowercase().contains(name),
+        }
+    }
 }
 
-pub unsafe fn check_physical_device(
-    instance: &Instance,
-    data: &AppData,
+/// What is logged about each physical device when one is picked.
+#[derive(Clone, Debug)]
+struct DeviceInfo {
+    index: usize,
     physical_device: vk::PhysicalDevice,
-) -> Result<()> {
+    name: String,
+    device_type: vk::PhysicalDeviceType,
+    /// Only known when both the instance and the device support Vulkan 1.1.
+    uuid: Option<[u8; vk::UUID_SIZE]>,
+    /// The total size of the device local memory heaps.
+    memory: vk::DeviceSize,
+    /// The rank and negotiated capabilities of a suitable device, or why an
+    /// unsuitable device was rejected.
+    suitability: Result<(u32, DeviceCapabilities), String>,
+}
 
-    QueueFamilyIndices::get(instance, data, physical_device)?;
+/// Picks the physical device named by the `device` setting, or otherwise the
+/// suitable device with the highest score.
+///
+/// Every device is logged along with its score or why it was rejected.
+pub unsafe fn pick_physical_device(instance: &Instance, data: &mut AppData) -> Result<()> {
+    let requirements = device_requirements(data);
+    let devices = instance
+        .enumerate_physical_devices()?
+        .into_iter()
+        .enumerate()
+        .map(|(index, physical_device)| get_device_info(instance, data, &requirements, index, physical_device))
+        .collect::<Result<Vec<_>>>()?;
+
+    let selector = data.settings.device.as_deref().map(DeviceSelector::parse);
+    let selected = match &selector {
+        Some(selector) => devices.iter().find(|d| selector.matches(d)),
+        // The first of equally ranked devices is picked.
+        None => devices
+            .iter()
+            .filter_map(|d| d.suitability.as_ref().ok().map(|(score, _)| (d, *score)))
+            .max_by_key(|(d, score)| (*score, Reverse(d.index)))
+            .map(|(d, _)| d),
+    };
 
-    let properties = instance
-        .get_physical_device_properties(physical_device);
-    if properties.device_type != vk::PhysicalDeviceType::DISCRETE_GPU {
-        return Err(anyhow!(SuitabilityError("Only discrete GPUs are supported.")));
+    log_devices(&devices, selected.map(|d| d.index));
+
+    let device = match (selected, &data.settings.device) {
+        (Some(device), _) => device,
+        (None, Some(name)) => return Err(anyhow!("No physical device matches `{}`.", name)),
+        (None, None) => return Err(anyhow!("Failed to find suitable physical device.")),
+    };
+
+    let capabilities = match &device.suitability {
+        Ok((_, capabilities)) => capabilities.clone(),
+        Err(error) => return Err(anyhow!("Physical device `{}` is not suitable: {}", device.name, error)),
+    };
+
+    info!("Selected physical device (`{}`, Vulkan {}).", device.name, capabilities.api_version);
+    debug!("Enabled device capabilities: {:?}", capabilities);
+    data.physical_device = device.physical_device;
+    data.capabilities = capabilities;
+    data.msaa_samples = get_msaa_samples(instance, data, data.settings.msaa_samples);
+    data.min_sample_shading = get_min_sample_shading(data, data.settings.sample_shading);
+    if data.msaa_samples.bits() != data.settings.msaa_samples {
+        info!("Using {} sample(s) per pixel instead of {}.", data.msaa_samples.bits(), data.settings.msaa_samples);
     }
 
-    let features = instance
-        .get_physical_device_features(physical_device);
-    
-    if features.geometry_shader != vk::TRUE {
-        return Err(anyhow!(SuitabilityError("Missing geometry shader support.")));
+    Ok(())
+}
+
+/// Collects the extensions and features every subsystem needs or can use.
+fn device_requirements(data: &AppData) -> DeviceRequirements {
+    let mut requirements = DeviceRequirements::default();
+    swapchain::declare_requirements(data, &mut requirements);
+    image::declare_requirements(data, &mut requirements);
+    msaa::declare_requirements(data, &mut requirements);
+    pipeline::declare_requirements(data, &mut requirements);
+    render_graph::declare_requirements(data, &mut requirements);
+    syncronization::declare_requirements(data, &mut requirements);
+    stats::declare_requirements(data, &mut requirements);
+
+    // Must be enabled wherever it is supported, as on MoltenVK.
+    if cfg!(target_os = "macos") {
+        requirements.request_extension(vk::KHR_PORTABILITY_SUBSET_EXTENSION.name);
     }
-    if features.sampler_anisotropy != vk::TRUE {
-        return Err(anyhow!(SuitabilityError("No sampler anisotropy.")));
+
+    requirements
+}
+
+unsafe fn get_device_info(
+    instance: &Instance,
+    data: &AppData,
+    requirements: &DeviceRequirements,
+    index: usize,
+    physical_device: vk::PhysicalDevice,
+) -> Result<DeviceInfo> {
+    let properties = instance.get_physical_device_properties(physical_device);
+
+    let uuid = if data.instance_version >= Version::V1_1_0 && Version::from(properties.api_version) >= Version::V1_1_0 {
+        let mut id_properties = vk::PhysicalDeviceIDProperties::default();
+        let mut properties2 = vk::PhysicalDeviceProperties2::builder().push_next(&mut id_properties);
+        instance.get_physical_device_properties2(physical_device, &mut properties2);
+        Some(*id_properties.device_uuid)
+    } else {
+        None
+    };
+
+    let memory_properties = instance.get_physical_device_memory_properties(physical_device);
+    let memory = memory_properties.memory_heaps[..memory_properties.memory_heap_count as usize]
+        .iter()
+        .filter(|h| h.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL))
+        .map(|h| h.size)
+        .sum();
+
+    let suitability = match check_physical_device(instance, data, requirements, physical_device) {
+        Ok(capabilities) => Ok((score_physical_device(&properties, memory, &capabilities), capabilities)),
+        Err(error) => Err(error.to_string()),
+    };
+
+    Ok(DeviceInfo {
+        index,
+        physical_device,
+        name: properties.device_name.to_string(),
+        device_type: properties.device_type,
+        uuid,
+        memory,
+        suitability,
+    })
+}
+
+/// Ranks a suitable physical device.
+///
+/// The device type matters most, preferring discrete over integrated over
+/// virtual over CPU devices. Ties are broken by the amount of device local
+/// memory and the optional extensions and features the device supports.
+fn score_physical_device(
+    properties: &vk::PhysicalDeviceProperties,
+    memory: vk::DeviceSize,
+    capabilities: &DeviceCapabilities,
+) -> u32 {
+    let mut score = match properties.device_type {
+        vk::PhysicalDeviceType::DISCRETE_GPU => 10_000,
+        vk::PhysicalDeviceType::INTEGRATED_GPU => 5_000,
+        vk::PhysicalDeviceType::VIRTUAL_GPU => 2_000,
+        vk::PhysicalDeviceType::CPU => 1_000,
+        _ => 0,
+    };
+
+    // Up to 64 GiB, in steps of 64 MiB.
+    score += (memory >> 26).min(1024) as u32;
+
+    score += capabilities.optional_supported as u32 * 100;
+
+    score
+}
+
+fn log_devices(devices: &[DeviceInfo], selected: Option<usize>) {
+    info!("{:<3} {:<40} {:<14} {:>10} {:>6}  {:<36}  Status", "#", "Name", "Type", "Memory", "Score", "UUID");
+
+    for device in devices {
+        let device_type = match device.device_type {
+            vk::PhysicalDeviceType::DISCRETE_GPU => "discrete GPU",
+            vk::PhysicalDeviceType::INTEGRATED_GPU => "integrated GPU",
+            vk::PhysicalDeviceType::VIRTUAL_GPU => "virtual GPU",
+            vk::PhysicalDeviceType::CPU => "CPU",
+            _ => "other",
+        };
+
+        let memory = format!("{:.1} GiB", device.memory as f64 / (1024.0 * 1024.0 * 1024.0));
+        let uuid = device.uuid.map(|u| format_uuid(&u)).unwrap_or_else(|| "-".into());
+        let (score, status) = match &device.suitability {
+            Ok((score, _)) if selected == Some(device.index) => (score.to_string(), "selected".into()),
+            Ok((score, _)) => (score.to_string(), "suitable".into()),
+            Err(error) => ("-".into(), format!("rejected: {}", error)),
+        };
+
+        info!(
+            "{:<3} {:<40} {:<14} {:>10} {:>6}  {:<36}  {}",
+            device.index,
+            device.name,
+            device_type,
+            memory,
+            score,
+            uuid,
+            status,
+        );
     }
+}
+
+fn format_uuid(uuid: &[u8; vk::UUID_SIZE]) -> String {
+    let hex = uuid.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+/// Checks that a physical device meets every requirement of the renderer,
+/// returning what would be enabled on it.
+pub unsafe fn check_physical_device(
+    instance: &Instance,
+    data: &AppData,
+    requirements: &DeviceRequirements,
+    physical_device: vk::PhysicalDevice,
+) -> Result<DeviceCapabilities> {
+    let capabilities = requirements.negotiate(instance, data, physical_device)?;
+
     QueueFamilyIndices::get(instance, data, physical_device)?;
 
-    check_physical_device_extensions(instance, physical_device)?;
+    if data.headless {
+        return Ok(capabilities);
+    }
 
     let support = SwapchainSupport::get(instance, data, physical_device)?;
     if support.formats.is_empty() || support.present_modes.is_empty() {
         return Err(anyhow!(SuitabilityError("Insufficient swapchain support.")));
     }
 
-
-    Ok(())
+    Ok(capabilities)
 }
 
 
 
+/// Creates the logical device with the capabilities negotiated by [`pick_physical_device`].
 pub unsafe fn create_logical_device(
-    entry: &Entry,
-    instance: &Instance,
+    instance: &SharedInstance,
     data: &mut AppData,
-) -> Result<Device> {
+) -> Result<SharedDevice> {
     let indices = QueueFamilyIndices::get(instance, data, data.physical_device)?;
 
-    let layers = if VALIDATION_ENABLED {
+    let layers = if data.settings.validation {
         vec![VALIDATION_LAYER.as_ptr()]
     } else {
         vec![]
     };
 
-    let mut extensions = DEVICE_EXTENSIONS
-        .iter()
-        .map(|n| n.as_ptr())
-        .collect::<Vec<_>>();
-
-    // Required by Vulkan SDK on macOS since 1.3.216.
-    if cfg!(target_os = "macos") && entry.version()? >= PORTABILITY_MACOS_VERSION {
-        extensions.push(vk::KHR_PORTABILITY_SUBSET_EXTENSION.name.as_ptr());
-    }
-
-    let features = vk::PhysicalDeviceFeatures::builder()
-        .sampler_anisotropy(true);
+    let extensions = data.capabilities.extension_names();
 
     let indices = QueueFamilyIndices::get(instance, data, data.physical_device)?;
 
@@ -119,33 +309,29 @@ pub unsafe fn create_logical_device(
         })
         .collect::<Vec<_>>();
 
-    
     let info = vk::DeviceCreateInfo::builder()
         .queue_create_infos(&queue_infos)
         .enabled_layer_names(&layers)
-        .enabled_extension_names(&extensions)
-        .enabled_features(&features);
-
+        .enabled_extension_names(&extensions);
+
+    // Chained feature structs replace `pEnabledFeatures`, which must then be null.
+    let device = data.capabilities.with_enabled_features(|features, features2| match features2 {
+        Some(features2) => instance.create_device(data.physical_device, &info.push_next(features2), None),
+        None => instance.create_device(data.physical_device, &info.enabled_features(features), None),
+    })?;
+    data.queue_families = indices;
+    data.render_backend = RenderBackend::select(&data.capabilities);
+    if data.render_backend == RenderBackend::Dynamic {
+        info!("Rendering with dynamic rendering and synchronization2.");
+    }
+    if indices.transfer != indices.graphics {
+        info!("Uploading on dedicated transfer queue family {}.", indices.transfer);
+    }
 
-    let device = instance.create_device(data.physical_device, &info, None)?;
     data.present_queue = device.get_device_queue(indices.present, 0);
     data.transfer_queue = device.get_device_queue(indices.transfer, 0);
     data.graphics_queue = device.get_device_queue(indices.graphics, 0);
-    Ok(device)
-}   
 
-pub unsafe fn check_physical_device_extensions(
-    instance: &Instance,
-    physical_device: vk::PhysicalDevice,
-) -> Result<()> {
-    let extensions = instance
-        .enumerate_device_extension_properties(physical_device, None)?
-        .iter()
-        .map(|e| e.extension_name)
-        .collect::<HashSet<_>>();
-    if DEVICE_EXTENSIONS.iter().all(|e| extensions.contains(e)) {
-        Ok(())
-    } else {
-        Err(anyhow!(SuitabilityError("Missing required device extensions.")))
-    }
-}
\ No newline at end of file
+    let allocator = Allocator::new(instance, data.physical_device);
+    Ok(Rc::new(OwnedDevice::new(instance, device, allocator, HandleTracker::new(data.settings.validation))))
+}   
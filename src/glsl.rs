@@ -0,0 +1,31 @@
+//! GLSL to SPIR-V compilation, shared by `build.rs` and shader hot reloading.
+
+use std::fs;
+use std::path::Path;
+
+use bevy_glsl_to_spirv::{compile, ShaderType};
+
+
+/// Gets the shader stage of a GLSL file from its extension.
+pub fn get_shader_type(path: &Path) -> Option<ShaderType> {
+    match path.extension()?.to_str()? {
+        "vert" => Some(ShaderType::Vertex),
+        "frag" => Some(ShaderType::Fragment),
+        "geom" => Some(ShaderType::Geometry),
+        "tesc" => Some(ShaderType::TessellationControl),
+        "tese" => Some(ShaderType::TessellationEvaluation),
+        "comp" => Some(ShaderType::Compute),
+        _ => None,
+    }
+}
+
+/// Compiles a GLSL file to SPIR-V bytecode, returning the compiler log on failure.
+pub fn compile_shader(path: &Path) -> Result<Vec<u8>, String> {
+    let type_ = get_shader_type(path)
+        .ok_or_else(|| format!("Unknown shader stage for `{}`.", path.display()))?;
+
+    let source = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let words = compile(&source, type_, None)?;
+
+    Ok(words.iter().flat_map(|w| w.to_le_bytes()).collect())
+}
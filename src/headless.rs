@@ -0,0 +1,162 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use vulkanalia::vk::{DeviceV1_0, HasBuilder};
+use vulkanalia::{vk, Instance};
+
+use crate::buffer::{create_buffer, create_image};
+use crate::command::{begin_single_time_commands, end_single_time_commands};
+use crate::image::create_image_view;
+use crate::owned::SharedDevice;
+
+use crate::app_data::AppData;
+use anyhow::Result;
+
+
+/// The format of the offscreen color target, matching the sRGB swapchain format.
+pub const OFFSCREEN_FORMAT: vk::Format = vk::Format::R8G8B8A8_SRGB;
+
+
+/// Creates the offscreen color target used in place of the swapchain images.
+pub unsafe fn create_offscreen_target(
+    instance: &Instance,
+    device: &SharedDevice,
+    data: &mut AppData,
+    width: u32,
+    height: u32,
+) -> Result<()> {
+    data.offscreen_image = create_image(
+        device,
+        width,
+        height,
+        1,
+        vk::SampleCountFlags::_1,
+        OFFSCREEN_FORMAT,
+        vk::ImageTiling::OPTIMAL,
+        vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC,
+        vk::MemoryPropertyFlags::DEVICE_LOCAL,
+    )?;
+
+    // The rest of the renderer only knows about swapchain images, so the
+    // offscreen target stands in as a single-image "swapchain".
+
+    data.swapchain_format = OFFSCREEN_FORMAT;
+    data.swapchain_extent = vk::Extent2D { width, height };
+    data.swapchain_images = vec![*data.offscreen_image];
+    data.swapchain_image_views = vec![create_image_view(
+        device,
+        *data.offscreen_image,
+        OFFSCREEN_FORMAT,
+        1,
+        vk::ImageAspectFlags::COLOR,
+    )?
+    .depends_on(&data.offscreen_image)];
+
+    Ok(())
+}
+
+/// Copies the rendered offscreen target into host memory and writes it to a PNG file.
+pub unsafe fn save_offscreen_png(
+    instance: &Instance,
+    device: &SharedDevice,
+    data: &AppData,
+    path: &Path,
+) -> Result<()> {
+    let vk::Extent2D { width, height } = data.swapchain_extent;
+    let size = u64::from(width) * u64::from(height) * 4;
+
+    let staging_buffer = create_buffer(
+        device,
+        size,
+        vk::BufferUsageFlags::TRANSFER_DST,
+        vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
+    )?;
+
+    let command_buffer = begin_single_time_commands(device, data)?;
+
+    let subresource = vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .level_count(1)
+        .base_array_layer(0)
+        .layer_count(1);
+
+    // The render graph leaves the image in `TRANSFER_SRC_OPTIMAL`, so this
+    // only has to make the color writes visible to the copy.
+    let barrier = vk::ImageMemoryBarrier::builder()
+        .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+        .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .image(*data.offscreen_image)
+        .subresource_range(subresource)
+        .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+        .dst_access_mask(vk::AccessFlags::TRANSFER_READ);
+
+    device.cmd_pipeline_barrier(
+        command_buffer,
+        vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+        vk::PipelineStageFlags::TRANSFER,
+        vk::DependencyFlags::empty(),
+        &[] as &[vk::MemoryBarrier],
+        &[] as &[vk::BufferMemoryBarrier],
+        &[barrier],
+    );
+
+    let subresource = vk::ImageSubresourceLayers::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .mip_level(0)
+        .base_array_layer(0)
+        .layer_count(1);
+
+    let region = vk::BufferImageCopy::builder()
+        .buffer_offset(0)
+        .buffer_row_length(0)
+        .buffer_image_height(0)
+        .image_subresource(subresource)
+        .image_offset(vk::Offset3D { x: 0, y: 0, z: 0 })
+        .image_extent(vk::Extent3D { width, height, depth: 1 });
+
+    device.cmd_copy_image_to_buffer(
+        command_buffer,
+        *data.offscreen_image,
+        vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+        *staging_buffer,
+        &[region],
+    );
+
+    let barrier = vk::BufferMemoryBarrier::builder()
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .buffer(*staging_buffer)
+        .offset(0)
+        .size(size)
+        .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+        .dst_access_mask(vk::AccessFlags::HOST_READ);
+
+    device.cmd_pipeline_barrier(
+        command_buffer,
+        vk::PipelineStageFlags::TRANSFER,
+        vk::PipelineStageFlags::HOST,
+        vk::DependencyFlags::empty(),
+        &[] as &[vk::MemoryBarrier],
+        &[barrier],
+        &[] as &[vk::ImageMemoryBarrier],
+    );
+
+    end_single_time_commands(device, data, command_buffer)?;
+
+    let pixels = std::slice::from_raw_parts(staging_buffer.memory().mapped_ptr()?, size as usize).to_vec();
+    drop(staging_buffer);
+
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pixels)?;
+
+    Ok(())
+}
@@ -0,0 +1,172 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Result;
+use bevy_glsl_to_spirv::ShaderType;
+use log::*;
+
+use crate::app_data::AppData;
+use crate::glsl::{compile_shader, get_shader_type};
+use crate::owned::SharedDevice;
+use crate::pipeline::{create_pipeline, reflect_shaders};
+
+
+/// How often the shader directory is scanned for changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+
+/// Watches a directory of GLSL shaders for modifications.
+///
+/// Modified shaders stay pending until they have been reloaded, so a shader
+/// that compiled is not lost when another one in the same change fails.
+#[derive(Clone, Debug)]
+pub struct ShaderWatcher {
+    directory: PathBuf,
+    modified: HashMap<PathBuf, SystemTime>,
+    pending: HashSet<PathBuf>,
+    last_poll: Instant,
+}
+
+impl ShaderWatcher {
+    pub fn new(directory: impl Into<PathBuf>) -> Result<Self> {
+        let directory = directory.into();
+        let modified = scan_shaders(&directory)?;
+        info!("Watching `{}` for shader changes.", directory.display());
+        Ok(Self { directory, modified, pending: HashSet::new(), last_poll: Instant::now() })
+    }
+
+    /// Returns every pending shader if any were modified since the last poll.
+    pub fn poll(&mut self) -> Vec<PathBuf> {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return vec![];
+        }
+
+        self.last_poll = Instant::now();
+
+        let modified = match scan_shaders(&self.directory) {
+            Ok(modified) => modified,
+            Err(e) => {
+                warn!("Failed to scan `{}`: {}", self.directory.display(), e);
+                return vec![];
+            }
+        };
+
+        let changed = modified
+            .iter()
+            .filter(|(p, t)| self.modified.get(*p) != Some(*t))
+            .map(|(p, _)| p.clone())
+            .collect::<Vec<_>>();
+
+        self.modified = modified;
+        if changed.is_empty() {
+            return vec![];
+        }
+
+        self.pending.extend(changed);
+        self.pending.iter().cloned().collect()
+    }
+
+    /// Forgets the pending shaders once they have been reloaded.
+    pub fn reloaded(&mut self) {
+        self.pending.clear();
+    }
+}
+
+fn scan_shaders(directory: &Path) -> Result<HashMap<PathBuf, SystemTime>> {
+    let mut modified = HashMap::new();
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if get_shader_type(&path).is_some() {
+            modified.insert(path.clone(), fs::metadata(&path)?.modified()?);
+        }
+    }
+
+    Ok(modified)
+}
+
+/// Recompiles the changed shaders and rebuilds the pipelines that use them,
+/// returning whether the changes were applied.
+///
+/// Every changed shader is compiled, with its stage taken from its extension.
+/// If any fails to compile or the new pipeline cannot be created, the error
+/// is logged and the previous pipeline is kept.
+pub unsafe fn reload_shaders(device: &SharedDevice, data: &mut AppData, changed: &[PathBuf]) -> Result<bool> {
+    let mut vert_shader = data.vert_shader.clone();
+    let mut frag_shader = data.frag_shader.clone();
+    let mut failed = false;
+
+    for path in changed {
+        let bytecode = match compile_shader(path) {
+            Ok(bytecode) => bytecode,
+            Err(e) => {
+                error!("Failed to compile `{}`:\n{}", path.display(), e.trim_end());
+                failed = true;
+                continue;
+            }
+        };
+
+        match get_shader_type(path) {
+            Some(ShaderType::Vertex) => vert_shader = bytecode,
+            Some(ShaderType::Fragment) => frag_shader = bytecode,
+            _ => debug!("`{}` is not used by any pipeline.", path.display()),
+        }
+    }
+
+    if failed {
+        error!("Keeping the previous pipeline until every changed shader compiles.");
+        return Ok(false);
+    }
+
+    if vert_shader == data.vert_shader && frag_shader == data.frag_shader {
+        return Ok(true);
+    }
+
+    let reflection = match reflect_shaders(&vert_shader, &frag_shader) {
+        Ok(reflection) => reflection,
+        Err(e) => {
+            error!("Failed to reflect shaders, keeping the previous pipeline: {}", e);
+            return Ok(false);
+        }
+    };
+
+    // Descriptor sets and the pipeline layout are created once from the original shaders.
+    if reflection.bindings != data.shader_reflection.bindings
+        || reflection.push_constant_ranges != data.shader_reflection.push_constant_ranges
+    {
+        error!("Descriptor bindings or push constants changed, keeping the previous pipeline (restart to apply).");
+        return Ok(false);
+    }
+
+    // Only the shaders that changed make cached pipelines stale.
+    let stale = [(&data.vert_shader, &vert_shader), (&data.frag_shader, &frag_shader)]
+        .into_iter()
+        .filter(|(old, new)| old != new)
+        .map(|(old, new)| (old.clone(), new.clone()))
+        .collect::<Vec<_>>();
+
+    let old_shaders = (
+        std::mem::replace(&mut data.vert_shader, vert_shader),
+        std::mem::replace(&mut data.frag_shader, frag_shader),
+        std::mem::replace(&mut data.shader_reflection, reflection),
+    );
+
+    // `create_pipeline` only replaces the pipeline once it has been created.
+    if let Err(e) = create_pipeline(device, data) {
+        error!("Failed to rebuild pipeline, keeping the previous one: {}", e);
+        let new_code = stale.iter().map(|(_, new)| new.as_slice()).collect::<Vec<_>>();
+        data.pipelines.evict_shaders(&new_code);
+        (data.vert_shader, data.frag_shader, data.shader_reflection) = old_shaders;
+        return Ok(false);
+    }
+
+    // Frames in flight may still use the pipelines of the old shaders.
+    let old_code = stale.iter().map(|(old, _)| old.as_slice()).collect::<Vec<_>>();
+    let old_pipelines = data.pipelines.evict_shaders(&old_code);
+    data.frame_sync.defer_drop(old_pipelines);
+
+    info!("Reloaded shaders.");
+
+    Ok(true)
+}
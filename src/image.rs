@@ -1,123 +1,125 @@
-use std::fs::File;
-use std::ptr::copy_nonoverlapping as memcpy;
-
 use vulkanalia::vk::{DeviceV1_0,  HasBuilder, InstanceV1_0};
 use vulkanalia::{vk, Device, Instance};
 
 
-use crate::command::{begin_single_time_commands, end_single_time_commands};
-
-use crate::buffer::{create_buffer,create_image};
+use crate::buffer::create_image;
 
 use crate::app_data::AppData;
+use crate::capabilities::{DeviceRequirements, Feature};
+use crate::owned::{Owned, SharedDevice};
 use anyhow::{anyhow,Result};
 
 
+/// Texture samplers always use anisotropic filtering.
+pub fn declare_requirements(data: &AppData, requirements: &mut DeviceRequirements) {
+    requirements.require_feature(Feature::SamplerAnisotropy);
+}
+
 
+/// Uploads RGBA8 pixels into a mipmapped texture image, returning the image and its mip level count.
+///
+/// The upload is recorded into the current batch of `data.uploads` and is
+/// only submitted when the batch is flushed.
 pub unsafe fn create_texture_image(
     instance: &Instance,
-    device: &Device,
+    device: &SharedDevice,
     data: &mut AppData,
-) -> Result<()> {
-    let image = File::open("resources/viking_room.png")?;
-
-    let decoder = png::Decoder::new(image);
-    let mut reader = decoder.read_info()?;
-
-    
-    print!("mip levels: {}", data.mip_levels);
-
-    let mut pixels = vec![0;  reader.info().raw_bytes()];
-    reader.next_frame(&mut pixels)?;
-
-    let size = reader.info().raw_bytes() as u64;
-    let (width, height) = reader.info().size();
-
-    data.mip_levels = (width.max(height) as f32).log2().floor() as u32 + 1;
-
-
-    let (staging_buffer, staging_buffer_memory) = create_buffer(
-        instance,
-        device,
-        data,
-        size,
-        vk::BufferUsageFlags::TRANSFER_SRC,
-        vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
-    )?;
-
-    let memory = device.map_memory(
-        staging_buffer_memory,
-        0,
-        size,
-        vk::MemoryMapFlags::empty(),
-    )?;
+    width: u32,
+    height: u32,
+    pixels: &[u8],
+) -> Result<(Owned<vk::Image>, u32)> {
+    let size = pixels.len() as u64;
+    if size != width as u64 * height as u64 * 4 {
+        return Err(anyhow!("Expected {}x{} RGBA8 pixels but got {} bytes.", width, height, size));
+    }
 
-    memcpy(pixels.as_ptr(), memory.cast(), pixels.len());
+    if !instance
+        .get_physical_device_format_properties(data.physical_device, vk::Format::R8G8B8A8_SRGB)
+        .optimal_tiling_features
+        .contains(vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR)
+    {
+        return Err(anyhow!("Texture image format does not support linear blitting!"));
+    }
 
-    device.unmap_memory(staging_buffer_memory);
+    let mip_levels = (width.max(height) as f32).log2().floor() as u32 + 1;
 
-    let (texture_image, texture_image_memory) = create_image(
-        instance,
+    let texture_image = create_image(
         device,
-        data,
         width,
         height,
-        data.mip_levels,
+        mip_levels,
+        vk::SampleCountFlags::_1,
         vk::Format::R8G8B8A8_SRGB,
         vk::ImageTiling::OPTIMAL,
         vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST  | vk::ImageUsageFlags::TRANSFER_SRC,
         vk::MemoryPropertyFlags::DEVICE_LOCAL,
     )?;
 
-    data.texture_image = texture_image;
-    data.texture_image_memory = texture_image_memory;
+    let uploads = &mut data.uploads;
+    let (staging_buffer, offset) = uploads.stage(device, pixels)?;
 
+    // The base level is copied on the transfer queue, but blits need the
+    // graphics queue, so the mipmaps are generated after an ownership transfer.
+    let transfer_commands = uploads.transfer_commands(device)?;
 
     transition_image_layout(
         device,
-        data,
-        data.texture_image,
+        transfer_commands,
+        *texture_image,
         vk::Format::R8G8B8A8_SRGB,
-        data.mip_levels,
+        mip_levels,
         vk::ImageLayout::UNDEFINED,
         vk::ImageLayout::TRANSFER_DST_OPTIMAL,
     )?;
     
     copy_buffer_to_image(
         device,
-        data,
+        transfer_commands,
         staging_buffer,
-        data.texture_image,
+        offset,
+        *texture_image,
         width,
         height,
-    )?;
+    );
+
+    let subresource = vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .level_count(mip_levels)
+        .base_array_layer(0)
+        .layer_count(1)
+        .build();
+
+    uploads.release_image(
+        device,
+        *texture_image,
+        subresource,
+        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+        vk::PipelineStageFlags::TRANSFER,
+        vk::AccessFlags::TRANSFER_READ | vk::AccessFlags::TRANSFER_WRITE,
+    );
 
     generate_mipmaps(
-        instance,
         device,
-        data,
-        data.texture_image,
-        vk::Format::R8G8B8A8_SRGB,
+        uploads.graphics_commands(device)?,
+        *texture_image,
         width,
         height,
-        data.mip_levels,
-    )?;
- 
-    device.destroy_buffer(staging_buffer, None);
-    device.free_memory(staging_buffer_memory, None);
+        mip_levels,
+    );
 
-    Ok(())
+    Ok((texture_image, mip_levels))
 }
 unsafe fn transition_image_layout(
     device: &Device,
-    data: &AppData,
+    command_buffer: vk::CommandBuffer,
     image: vk::Image,
     format: vk::Format,
     mip_levels: u32,
     old_layout: vk::ImageLayout,
     new_layout: vk::ImageLayout,
 ) -> Result<()> {
-    let command_buffer = begin_single_time_commands(device, data)?;
 
     let aspect_mask = if new_layout == vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL {
         match format {
@@ -182,22 +184,18 @@ unsafe fn transition_image_layout(
         &[barrier],
     );
 
-   
-    end_single_time_commands(device, data, command_buffer)?;
-
     Ok(())
 }
 
 unsafe fn copy_buffer_to_image(
     device: &Device,
-    data: &AppData,
+    command_buffer: vk::CommandBuffer,
     buffer: vk::Buffer,
+    offset: vk::DeviceSize,
     image: vk::Image,
     width: u32,
     height: u32,
-) -> Result<()> {
-    let command_buffer = begin_single_time_commands(device, data)?;
-
+) {
 
     let subresource = vk::ImageSubresourceLayers::builder()
         .aspect_mask(vk::ImageAspectFlags::COLOR)
@@ -206,7 +204,7 @@ unsafe fn copy_buffer_to_image(
         .layer_count(1);
 
     let region = vk::BufferImageCopy::builder()
-        .buffer_offset(0)
+        .buffer_offset(offset)
         .buffer_row_length(0)
         .buffer_image_height(0)
         .image_subresource(subresource)
@@ -220,31 +218,17 @@ unsafe fn copy_buffer_to_image(
         vk::ImageLayout::TRANSFER_DST_OPTIMAL,
         &[region],
     );
-    end_single_time_commands(device, data, command_buffer)?;
-
-    Ok(())
 }
 
 
-pub unsafe fn create_texture_image_view(device: &Device, data: &mut AppData) -> Result<()> {
-    data.texture_image_view = create_image_view(
-        device,
-        data.texture_image,
-        vk::Format::R8G8B8A8_SRGB,
-        data.mip_levels,
-        vk::ImageAspectFlags::COLOR,
-    )?;
-
-    Ok(())
-}
-
+#[track_caller]
 pub unsafe fn create_image_view(
-    device: &Device,
+    device: &SharedDevice,
     image: vk::Image,
     format: vk::Format,
     mip_levels: u32,
     aspects : vk::ImageAspectFlags
-) -> Result<vk::ImageView> {
+) -> Result<Owned<vk::ImageView>> {
     let subresource_range = vk::ImageSubresourceRange::builder()
         .aspect_mask(aspects)
         .base_mip_level(0)
@@ -258,10 +242,11 @@ pub unsafe fn create_image_view(
         .format(format)
         .subresource_range(subresource_range);
 
-    Ok(device.create_image_view(&info, None)?)
+    Ok(Owned::new(device, device.create_image_view(&info, None)?))
 }
 
-pub unsafe fn create_texture_sampler(device: &Device, data: &mut AppData) -> Result<()> {
+/// Creates a sampler that can be used with textures of any size.
+pub unsafe fn create_texture_sampler(device: &SharedDevice) -> Result<Owned<vk::Sampler>> {
 
     let info = vk::SamplerCreateInfo::builder()
         .mag_filter(vk::Filter::LINEAR)
@@ -278,48 +263,12 @@ pub unsafe fn create_texture_sampler(device: &Device, data: &mut AppData) -> Res
         .mipmap_mode(vk::SamplerMipmapMode::LINEAR)
         .mip_lod_bias(0.0)
         .min_lod(0.0)
-        .max_lod(data.mip_levels as f32);
+        .max_lod(vk::LOD_CLAMP_NONE);
    
-    data.texture_sampler = device.create_sampler(&info, None)?;
-
-
-    
-    Ok(())
+    Ok(Owned::new(device, device.create_sampler(&info, None)?))
 }
 
 
-pub unsafe fn create_depth_objects(
-    instance: &Instance,
-    device: &Device,
-    data: &mut AppData,
-) -> Result<()> {
-
-    let format = get_depth_format(instance, data)?;
-
-    let (depth_image, depth_image_memory) = create_image(
-        instance,
-        device,
-        data,
-        data.swapchain_extent.width,
-        data.swapchain_extent.height,
-        1,
-        format,
-        vk::ImageTiling::OPTIMAL,
-        vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
-        vk::MemoryPropertyFlags::DEVICE_LOCAL,
-    )?;
-
-    data.depth_image = depth_image;
-    data.depth_image_memory = depth_image_memory;
-
-    // Image View
-
-    data.depth_image_view = create_image_view(device, data.depth_image, format, 1, vk::ImageAspectFlags::DEPTH)?;
-    
-
-    Ok(())
-}
-
 pub unsafe fn get_depth_format(instance: &Instance, data: &AppData) -> Result<vk::Format> {
     let candidates = &[
         vk::Format::D32_SFLOAT,
@@ -360,27 +309,17 @@ unsafe fn get_supported_format(
         .ok_or_else(|| anyhow!("Failed to find supported format!"))
 }
 
+/// Blits each mip level from the previous one, leaving every level ready to be sampled.
+///
+/// The image format must support linear blitting.
 unsafe fn generate_mipmaps(
-    instance: &Instance,
     device: &Device,
-    data: &AppData,
+    command_buffer: vk::CommandBuffer,
     image: vk::Image,
-    format: vk::Format,
     width: u32,
     height: u32,
     mip_levels: u32,
-) -> Result<()> {
-
-    if !instance
-        .get_physical_device_format_properties(data.physical_device, format)
-        .optimal_tiling_features
-        .contains(vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR)
-    {
-        return Err(anyhow!("Texture image format does not support linear blitting!"));
-    }
-
-    let command_buffer = begin_single_time_commands(device, data)?;
-
+) {
     let subresource = vk::ImageSubresourceRange::builder()
         .aspect_mask(vk::ImageAspectFlags::COLOR)
         .base_array_layer(0)
@@ -499,9 +438,4 @@ unsafe fn generate_mipmaps(
         &[] as &[vk::BufferMemoryBarrier],
         &[barrier],
     );
-
-
-    end_single_time_commands(device, data, command_buffer)?;
-
-    Ok(())
 }
\ No newline at end of file
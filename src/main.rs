@@ -5,33 +5,52 @@
     clippy::unnecessary_wraps
 )]
 
+mod allocator;
 mod app;
 mod app_data;
 mod buffer;
+mod camera;
+mod capabilities;
 mod command;
 mod debug;
 mod device;
+mod glsl;
+mod headless;
+mod hot_reload;
 mod image;
 mod queue_family;
 mod pipeline;
+mod pipeline_cache;
+mod pipeline_desc;
+mod profiler;
+mod reflect;
+mod render_graph;
 mod shader;
+mod stats;
 mod swapchain;
+mod upload;
 mod syncronization;
 mod mesh;
+mod model;
 mod msaa;
+mod owned;
+mod scene;
+mod settings;
 
-
-use log::*;
 use thiserror::Error;
 
 use vulkanalia::Version;
 
-use anyhow:: Result;
+use anyhow::Result;
+use clap::Parser;
+use std::path::Path;
 use winit::dpi::LogicalSize;
 use winit::event::{Event, WindowEvent};
 use winit::event_loop::{ControlFlow, EventLoop};
 use winit::window::WindowBuilder;
 
+use settings::{Args, Settings};
+
 const PORTABILITY_MACOS_VERSION: Version = Version::new(1, 3, 216);
 
 
@@ -39,44 +58,76 @@ const PORTABILITY_MACOS_VERSION: Version = Version::new(1, 3, 216);
 fn main() -> Result<()> {
     pretty_env_logger::init();
 
+    let args = Args::parse();
+    let settings = Settings::load(&args)?;
+
+    // Headless
+
+    if let Some(output) = &args.headless {
+        return run_headless(settings, output);
+    }
+
     // Window
 
     let event_loop = EventLoop::new();
     let window = WindowBuilder::new()
-        .with_title("Vulkan Tutorial (Rust)")
-        .with_inner_size(LogicalSize::new(1024, 768))
+        .with_title(&settings.title)
+        .with_inner_size(LogicalSize::new(settings.width, settings.height))
         .build(&event_loop)?;
 
     // App
 
-    let mut app = unsafe { app::App::create(&window)? };
-    let mut destroying = false;
+    let mut app = unsafe { app::App::create(&window, settings)? };
+    if args.hot_reload {
+        app.watch_shaders("shaders")?;
+    }
+
+    // The event loop never returns, so the app is taken out to be destroyed.
+    let mut app = Some(app);
     let mut minimized = false;
     event_loop.run(move |event, _, control_flow| {
         *control_flow = ControlFlow::Poll;
         match event {
             // Render a frame if our Vulkan app is not being destroyed.
-            Event::MainEventsCleared if !destroying  && !minimized =>
-                unsafe { app.render(&window) }.unwrap(),
+            Event::MainEventsCleared if !minimized => {
+                if let Some(app) = &mut app {
+                    unsafe { app.render(&window) }.unwrap();
+                }
+            }
             // Destroy our Vulkan app.
             Event::WindowEvent { event: WindowEvent::CloseRequested, .. } => {
-                destroying = true;
                 *control_flow = ControlFlow::Exit;
-                unsafe { app.destroy(); }
+                if let Some(app) = app.take() {
+                    unsafe { app.destroy(); }
+                }
             }
             Event::WindowEvent { event: WindowEvent::Resized(size), .. } => {
                 if size.width == 0 || size.height == 0 {
                     minimized = true;
-                } else {
+                } else if let Some(app) = &mut app {
                     minimized = false;
                     app.resized = true;
                 }
             }
+            Event::WindowEvent { event, .. } => {
+                if let Some(app) = &mut app {
+                    unsafe { app.handle_window_event(&event) }.unwrap();
+                }
+            }
             _ => {}
         }
     });
 }
 
+/// Renders a single frame at the configured resolution without a window.
+fn run_headless(settings: Settings, output: &Path) -> Result<()> {
+    let mut app = unsafe { app::App::create_headless(settings)? };
+    let result = unsafe { app.render_headless(output) };
+    unsafe { app.destroy() };
+
+    result
+}
+
 #[derive(Debug, Error)]
 #[error("{0}")]
 pub struct SuitabilityError(pub &'static str);
\ No newline at end of file
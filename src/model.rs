@@ -0,0 +1,371 @@
+//! Models loaded from files, before anything is uploaded to the GPU.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use cgmath::{vec2, vec3, Deg, SquareMatrix};
+use log::*;
+
+use crate::mesh::{Mat4, Vertex};
+
+
+/// A range of a mesh's indices drawn with a single material.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Submesh {
+    pub first_index: u32,
+    pub index_count: u32,
+    /// An index into [`Model::materials`].
+    pub material: Option<usize>,
+}
+
+/// Geometry that shares a vertex and index buffer.
+#[derive(Clone, Debug, Default)]
+pub struct ModelMesh {
+    pub vertices: Vec<Vertex>,
+    pub indices: Vec<u32>,
+    pub submeshes: Vec<Submesh>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Material {
+    pub name: String,
+    /// Multiplied with the base color texture; the alpha is the opacity.
+    pub base_color: [f32; 4],
+    /// An index into [`Model::images`].
+    pub base_color_texture: Option<usize>,
+    /// Drawn with alpha blending instead of opaque.
+    pub transparent: bool,
+    /// Maps that aren't sampled yet, kept as paths so they aren't decoded.
+    pub specular_map: Option<PathBuf>,
+    pub normal_map: Option<PathBuf>,
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            base_color: [1.0; 4],
+            base_color_texture: None,
+            transparent: false,
+            specular_map: None,
+            normal_map: None,
+        }
+    }
+}
+
+/// Decoded RGBA8 pixels.
+#[derive(Clone, Debug, Default)]
+pub struct ImageData {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl ImageData {
+    /// A single pixel of a linear RGBA color, encoded as sRGB like every other texture.
+    pub fn solid(color: [f32; 4]) -> Self {
+        let [r, g, b, a] = color;
+        let pixels = vec![to_u8(to_srgb(r)), to_u8(to_srgb(g)), to_u8(to_srgb(b)), to_u8(a)];
+        Self { width: 1, height: 1, pixels }
+    }
+}
+
+/// A mesh placed in the world.
+#[derive(Copy, Clone, Debug)]
+pub struct ModelNode {
+    /// An index into [`Model::meshes`].
+    pub mesh: usize,
+    pub transform: Mat4,
+}
+
+/// The meshes, materials and images of a file, along with where each mesh is drawn.
+#[derive(Clone, Debug, Default)]
+pub struct Model {
+    pub meshes: Vec<ModelMesh>,
+    pub materials: Vec<Material>,
+    pub images: Vec<ImageData>,
+    pub nodes: Vec<ModelNode>,
+}
+
+
+/// Loads a glTF 2.0 file (`.gltf` or `.glb`), flattening its node hierarchy.
+///
+/// Each primitive becomes a [`ModelMesh`]. Only triangle lists are supported;
+/// other primitives are skipped with a warning. glTF is Y-up, so the scene is
+/// rotated to match the Z-up convention of the rest of the renderer. Only
+/// the images used as base color textures are decoded.
+pub fn load_gltf(path: &Path) -> Result<Model> {
+    let gltf::Gltf { document, blob } = gltf::Gltf::open(path)?;
+    let directory = path.parent().unwrap_or_else(|| Path::new("./"));
+    let buffers = gltf::import_buffers(&document, Some(directory), blob)?;
+
+    let mut model = Model::default();
+
+    // The model image of each glTF image, decoded on first use.
+    let mut images = HashMap::<usize, usize>::new();
+
+    for material in document.materials() {
+        let pbr = material.pbr_metallic_roughness();
+        let base_color_texture = match pbr.base_color_texture() {
+            Some(info) => {
+                let image = info.texture().source();
+                Some(match images.get(&image.index()) {
+                    Some(index) => *index,
+                    None => {
+                        let data = gltf::image::Data::from_source(image.source(), Some(directory), &buffers)?;
+                        model.images.push(convert_image(data)?);
+                        images.insert(image.index(), model.images.len() - 1);
+                        model.images.len() - 1
+                    }
+                })
+            }
+            None => None,
+        };
+
+        // Embedded normal maps have no path to keep.
+        let normal_map = material.normal_texture().and_then(|t| match t.texture().source().source() {
+            gltf::image::Source::Uri { uri, .. } if !uri.starts_with("data:") => Some(directory.join(uri)),
+            _ => None,
+        });
+
+        model.materials.push(Material {
+            name: material.name().unwrap_or_default().to_string(),
+            base_color: pbr.base_color_factor(),
+            base_color_texture,
+            transparent: material.alpha_mode() == gltf::material::AlphaMode::Blend,
+            specular_map: None,
+            normal_map,
+        });
+    }
+
+    // The model meshes created for each primitive of each glTF mesh.
+    let mut primitives = HashMap::<usize, Vec<usize>>::new();
+    for mesh in document.meshes() {
+        for primitive in mesh.primitives() {
+            if primitive.mode() != gltf::mesh::Mode::Triangles {
+                warn!("Skipping {:?} primitive in mesh `{}`.", primitive.mode(), mesh.name().unwrap_or_default());
+                continue;
+            }
+
+            let reader = primitive.reader(|b| Some(&buffers[b.index()]));
+
+            let positions = reader
+                .read_positions()
+                .ok_or_else(|| anyhow!("Mesh `{}` has a primitive without positions.", mesh.name().unwrap_or_default()))?
+                .collect::<Vec<_>>();
+            let mut colors = reader.read_colors(0).map(|c| c.into_rgb_f32());
+            let mut tex_coords = reader.read_tex_coords(0).map(|t| t.into_f32());
+
+            let vertices = positions
+                .iter()
+                .map(|p| {
+                    let color = colors.as_mut().and_then(Iterator::next).unwrap_or([1.0; 3]);
+                    let tex_coord = tex_coords.as_mut().and_then(Iterator::next).unwrap_or([0.0; 2]);
+                    Vertex {
+                        pos: vec3(p[0], p[1], p[2]),
+                        color: vec3(color[0], color[1], color[2]),
+                        tex_coord: vec2(tex_coord[0], tex_coord[1]),
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            let indices: Vec<u32> = match reader.read_indices() {
+                Some(indices) => indices.into_u32().collect(),
+                None => (0..vertices.len() as u32).collect(),
+            };
+
+            primitives.entry(mesh.index()).or_default().push(model.meshes.len());
+            let submesh = Submesh {
+                first_index: 0,
+                index_count: indices.len() as u32,
+                material: primitive.material().index(),
+            };
+
+            model.meshes.push(ModelMesh { vertices, indices, submeshes: vec![submesh] });
+        }
+    }
+
+    let scene = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+        .ok_or_else(|| anyhow!("glTF file `{}` has no scenes.", path.display()))?;
+
+    let y_up_to_z_up = Mat4::from_angle_x(Deg(90.0));
+    for node in scene.nodes() {
+        add_nodes(&mut model, &primitives, &node, y_up_to_z_up);
+    }
+
+    Ok(model)
+}
+
+/// Loads an OBJ file and the MTL files it references.
+///
+/// The whole file becomes a single mesh with a [`Submesh`] for each object or
+/// group in it. Diffuse maps that can't be loaded are skipped with a warning,
+/// and materials that aren't fully opaque are drawn transparent.
+pub fn load_obj(path: &Path) -> Result<Model> {
+    let (models, materials) = tobj::load_obj(path, &tobj::GPU_LOAD_OPTIONS)?;
+
+    let materials = materials.unwrap_or_else(|e| {
+        warn!("Failed to load the materials of `{}`: {}", path.display(), e);
+        vec![]
+    });
+
+    let directory = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut model = Model::default();
+    let mut images = HashMap::new();
+
+    for material in &materials {
+        let base_color_texture = load_obj_texture(&mut model.images, &mut images, directory, &material.diffuse_texture);
+        let map_path = |name: &str| (!name.is_empty()).then(|| directory.join(name));
+
+        let [r, g, b] = material.diffuse;
+        model.materials.push(Material {
+            name: material.name.clone(),
+            base_color: [r, g, b, material.dissolve],
+            base_color_texture,
+            transparent: material.dissolve < 1.0,
+            specular_map: map_path(&material.specular_texture),
+            normal_map: map_path(&material.normal_texture),
+        });
+    }
+
+    let mut mesh = ModelMesh::default();
+    let mut unique_vertices = HashMap::new();
+
+    for object in &models {
+        let first_index = mesh.indices.len() as u32;
+
+        for index in &object.mesh.indices {
+            let index = *index as usize;
+
+            let color = if object.mesh.vertex_color.is_empty() {
+                vec3(1.0, 1.0, 1.0)
+            } else {
+                let color = &object.mesh.vertex_color[3 * index..];
+                vec3(color[0], color[1], color[2])
+            };
+
+            let tex_coord = if object.mesh.texcoords.is_empty() {
+                vec2(0.0, 0.0)
+            } else {
+                let tex_coord = &object.mesh.texcoords[2 * index..];
+                vec2(tex_coord[0], 1.0 - tex_coord[1])
+            };
+
+            let position = &object.mesh.positions[3 * index..];
+            let vertex = Vertex {
+                pos: vec3(position[0], position[1], position[2]),
+                color,
+                tex_coord,
+            };
+
+            if let Some(index) = unique_vertices.get(&vertex) {
+                mesh.indices.push(*index as u32);
+            } else {
+                let index = mesh.vertices.len();
+                unique_vertices.insert(vertex, index);
+                mesh.vertices.push(vertex);
+                mesh.indices.push(index as u32);
+            }
+        }
+
+        let material = object.mesh.material_id.filter(|m| *m < model.materials.len());
+        mesh.submeshes.push(Submesh {
+            first_index,
+            index_count: mesh.indices.len() as u32 - first_index,
+            material,
+        });
+    }
+
+    model.meshes.push(mesh);
+    model.nodes.push(ModelNode { mesh: 0, transform: Mat4::identity() });
+
+    Ok(model)
+}
+
+/// Loads a texture map named in an MTL file, relative to the OBJ file's directory.
+///
+/// Each file is only loaded once; `loaded` maps names to their index in `images`.
+fn load_obj_texture(
+    images: &mut Vec<ImageData>,
+    loaded: &mut HashMap<String, Option<usize>>,
+    directory: &Path,
+    name: &str,
+) -> Option<usize> {
+    if name.is_empty() {
+        return None;
+    }
+
+    *loaded.entry(name.to_string()).or_insert_with(|| {
+        match load_image(&directory.join(name)) {
+            Ok(image) => {
+                images.push(image);
+                Some(images.len() - 1)
+            }
+            Err(e) => {
+                warn!("{}", e);
+                None
+            }
+        }
+    })
+}
+
+/// Loads a PNG or JPEG file.
+pub fn load_image(path: &Path) -> Result<ImageData> {
+    let image = image::open(path)
+        .map_err(|e| anyhow!("Failed to load image `{}`: {}", path.display(), e))?
+        .into_rgba8();
+
+    Ok(ImageData { width: image.width(), height: image.height(), pixels: image.into_raw() })
+}
+
+fn add_nodes(
+    model: &mut Model,
+    primitives: &HashMap<usize, Vec<usize>>,
+    node: &gltf::Node,
+    parent: Mat4,
+) {
+    let transform = parent * Mat4::from(node.transform().matrix());
+
+    if let Some(meshes) = node.mesh().and_then(|m| primitives.get(&m.index())) {
+        for mesh in meshes {
+            model.nodes.push(ModelNode { mesh: *mesh, transform });
+        }
+    }
+
+    for child in node.children() {
+        add_nodes(model, primitives, &child, transform);
+    }
+}
+
+fn convert_image(image: gltf::image::Data) -> Result<ImageData> {
+    use gltf::image::Format;
+
+    let (width, height, pixels) = (image.width, image.height, image.pixels);
+    let pixels = match image.format {
+        Format::R8 => pixels.iter().flat_map(|r| [*r, *r, *r, 255]).collect(),
+        Format::R8G8 => pixels.chunks_exact(2).flat_map(|p| [p[0], p[1], 0, 255]).collect(),
+        Format::R8G8B8 => pixels.chunks_exact(3).flat_map(|p| [p[0], p[1], p[2], 255]).collect(),
+        Format::R8G8B8A8 => pixels,
+        // Only the high byte of each little-endian channel is kept.
+        Format::R16G16B16 => pixels.chunks_exact(6).flat_map(|p| [p[1], p[3], p[5], 255]).collect(),
+        Format::R16G16B16A16 => pixels.chunks_exact(8).flat_map(|p| [p[1], p[3], p[5], p[7]]).collect(),
+        format => return Err(anyhow!("Unsupported glTF image format {:?}.", format)),
+    };
+
+    Ok(ImageData { width, height, pixels })
+}
+
+fn to_u8(value: f32) -> u8 {
+    (value.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+fn to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
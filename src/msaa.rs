@@ -1,10 +1,21 @@
+use log::*;
 use vulkanalia::{vk::{self, InstanceV1_0}, Instance};
 
 use crate::app_data::AppData;
+use crate::capabilities::{DeviceRequirements, Feature};
 
-pub unsafe fn get_max_msaa_samples(
+/// Sample shading is used where supported, if requested.
+pub fn declare_requirements(data: &AppData, requirements: &mut DeviceRequirements) {
+    if data.settings.sample_shading.is_some() {
+        requirements.request_feature(Feature::SampleRateShading);
+    }
+}
+
+/// Gets the highest sample count the device supports that is no higher than `requested`.
+pub unsafe fn get_msaa_samples(
     instance: &Instance,
     data: &AppData,
+    requested: u32,
 ) -> vk::SampleCountFlags {
     let properties = instance.get_physical_device_properties(data.physical_device);
     let counts = properties.limits.framebuffer_color_sample_counts
@@ -19,6 +30,17 @@ pub unsafe fn get_max_msaa_samples(
     ]
     .iter()
     .cloned()
-    .find(|c| counts.contains(*c))
+    .find(|c| c.bits() <= requested && counts.contains(*c))
     .unwrap_or(vk::SampleCountFlags::_1)
-}
\ No newline at end of file
+}
+
+/// Gets the minimum sample shading fraction to use, if sample shading is requested and supported.
+pub fn get_min_sample_shading(data: &AppData, requested: Option<f32>) -> Option<f32> {
+    match requested {
+        Some(_) if !data.capabilities.has_feature(Feature::SampleRateShading) => {
+            warn!("Sample shading is not supported by the device and is disabled.");
+            None
+        }
+        requested => requested,
+    }
+}
@@ -0,0 +1,224 @@
+//! Owning wrappers for Vulkan handles that destroy themselves when dropped.
+//!
+//! Every [`Owned`] handle keeps the device it was created with alive, along
+//! with any handles it was declared to depend on (e.g., an image view keeps
+//! its image alive), so objects are always destroyed after their dependents
+//! no matter what order they are dropped in.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::fmt;
+use std::ops::Deref;
+use std::panic::Location;
+use std::rc::Rc;
+
+use vulkanalia::prelude::v1_0::*;
+use vulkanalia::vk::{ExtDebugUtilsExtension, KhrSurfaceExtension, KhrSwapchainExtension};
+
+use crate::allocator::{Allocation, Allocator};
+use crate::debug::HandleTracker;
+
+
+/// The instance, along with the instance-level objects created with it.
+pub struct OwnedInstance {
+    // Keeps the Vulkan library loaded for as long as the instance exists.
+    _entry: Entry,
+    instance: Instance,
+    pub messenger: vk::DebugUtilsMessengerEXT,
+    pub surface: vk::SurfaceKHR,
+}
+
+pub type SharedInstance = Rc<OwnedInstance>;
+
+impl OwnedInstance {
+    pub fn new(entry: Entry, instance: Instance) -> Self {
+        Self {
+            _entry: entry,
+            instance,
+            messenger: vk::DebugUtilsMessengerEXT::null(),
+            surface: vk::SurfaceKHR::null(),
+        }
+    }
+}
+
+impl Deref for OwnedInstance {
+    type Target = Instance;
+
+    fn deref(&self) -> &Self::Target {
+        &self.instance
+    }
+}
+
+impl Drop for OwnedInstance {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.surface.is_null() {
+                self.instance.destroy_surface_khr(self.surface, None);
+            }
+
+            if !self.messenger.is_null() {
+                self.instance.destroy_debug_utils_messenger_ext(self.messenger, None);
+            }
+
+            self.instance.destroy_instance(None);
+        }
+    }
+}
+
+/// The logical device, along with the allocator for its memory.
+pub struct OwnedDevice {
+    device: Device,
+    pub allocator: RefCell<Allocator>,
+    pub tracker: RefCell<HandleTracker>,
+    _instance: SharedInstance,
+}
+
+pub type SharedDevice = Rc<OwnedDevice>;
+
+impl OwnedDevice {
+    pub fn new(instance: &SharedInstance, device: Device, allocator: Allocator, tracker: HandleTracker) -> Self {
+        Self {
+            device,
+            allocator: RefCell::new(allocator),
+            tracker: RefCell::new(tracker),
+            _instance: instance.clone(),
+        }
+    }
+}
+
+impl Deref for OwnedDevice {
+    type Target = Device;
+
+    fn deref(&self) -> &Self::Target {
+        &self.device
+    }
+}
+
+impl Drop for OwnedDevice {
+    fn drop(&mut self) {
+        unsafe {
+            self.allocator.borrow_mut().destroy(&self.device);
+            self.device.destroy_device(None);
+        }
+    }
+}
+
+/// A Vulkan handle that can be destroyed with a device.
+pub trait Destroy: Handle<Repr = u64> + 'static {
+    unsafe fn destroy(self, device: &Device);
+}
+
+macro_rules! impl_destroy {
+    ($($type:ident => $function:ident),* $(,)?) => {
+        $(impl Destroy for vk::$type {
+            unsafe fn destroy(self, device: &Device) {
+                device.$function(self, None);
+            }
+        })*
+    };
+}
+
+impl_destroy! {
+    Buffer => destroy_buffer,
+    Image => destroy_image,
+    ImageView => destroy_image_view,
+    Sampler => destroy_sampler,
+    ShaderModule => destroy_shader_module,
+    Pipeline => destroy_pipeline,
+    PipelineCache => destroy_pipeline_cache,
+    PipelineLayout => destroy_pipeline_layout,
+    RenderPass => destroy_render_pass,
+    Framebuffer => destroy_framebuffer,
+    DescriptorSetLayout => destroy_descriptor_set_layout,
+    DescriptorPool => destroy_descriptor_pool,
+    CommandPool => destroy_command_pool,
+    QueryPool => destroy_query_pool,
+    Semaphore => destroy_semaphore,
+    Fence => destroy_fence,
+    SwapchainKHR => destroy_swapchain_khr,
+}
+
+struct Owner<T: Destroy> {
+    handle: T,
+    memory: Allocation,
+    device: SharedDevice,
+    // Dropped after `handle` is destroyed.
+    dependencies: Vec<Rc<dyn Any>>,
+}
+
+impl<T: Destroy> Drop for Owner<T> {
+    fn drop(&mut self) {
+        unsafe {
+            self.handle.destroy(&self.device);
+            self.device.allocator.borrow_mut().free(&self.device, self.memory);
+        }
+
+        self.device.tracker.borrow_mut().remove(T::TYPE, self.handle.as_raw());
+    }
+}
+
+/// A reference-counted owning wrapper for a Vulkan handle.
+///
+/// The default value is a null handle that owns nothing.
+pub struct Owned<T: Destroy> {
+    handle: T,
+    owner: Option<Rc<Owner<T>>>,
+}
+
+impl<T: Destroy> Owned<T> {
+    #[track_caller]
+    pub fn new(device: &SharedDevice, handle: T) -> Self {
+        Self::with_memory(device, handle, Allocation::default())
+    }
+
+    /// Wraps a buffer or image along with the memory bound to it.
+    ///
+    /// The caller is recorded as the creation site in the device's [`HandleTracker`].
+    #[track_caller]
+    pub fn with_memory(device: &SharedDevice, handle: T, memory: Allocation) -> Self {
+        device.tracker.borrow_mut().insert(T::TYPE, handle.as_raw(), Location::caller());
+
+        let owner = Owner { handle, memory, device: device.clone(), dependencies: vec![] };
+        Self { handle, owner: Some(Rc::new(owner)) }
+    }
+
+    /// Keeps `other` alive for at least as long as this handle.
+    pub fn depends_on<U: Destroy>(mut self, other: &Owned<U>) -> Self {
+        if let (Some(owner), Some(other)) = (self.owner.as_mut().and_then(Rc::get_mut), &other.owner) {
+            owner.dependencies.push(other.clone());
+        }
+
+        self
+    }
+
+    /// Gets the memory bound to this handle, if any.
+    pub fn memory(&self) -> Allocation {
+        self.owner.as_ref().map(|o| o.memory).unwrap_or_default()
+    }
+}
+
+impl<T: Destroy> Deref for Owned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.handle
+    }
+}
+
+impl<T: Destroy> Clone for Owned<T> {
+    fn clone(&self) -> Self {
+        Self { handle: self.handle, owner: self.owner.clone() }
+    }
+}
+
+impl<T: Destroy> Default for Owned<T> {
+    fn default() -> Self {
+        Self { handle: T::default(), owner: None }
+    }
+}
+
+impl<T: Destroy> fmt::Debug for Owned<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.handle.fmt(f)
+    }
+}
@@ -1,134 +1,100 @@
-use vulkanalia::{vk::{self, DeviceV1_0, Handle, HasBuilder}, Device};
+use std::mem::size_of;
+
+use vulkanalia::vk::{self, DeviceV1_0, HasBuilder};
 
 
 use crate::mesh::Vertex;
 use crate::app_data::AppData;
+use crate::capabilities::{DeviceRequirements, Feature};
+use crate::command::SCENE_PASS;
+use crate::owned::{Owned, SharedDevice};
+use crate::pipeline_desc::{BlendMode, PipelineDesc, ShaderStage};
+use crate::scene::PushConstants;
 
-use crate::shader::create_shader_module;
-use anyhow::Result;
-
-
-pub unsafe fn create_pipeline(device: &Device, data: &mut AppData) -> Result<()> {
-    let vert = include_bytes!("../shaders/vert.spv");
-    let frag = include_bytes!("../shaders/frag.spv");
-
-    let vert_shader_module = create_shader_module(device, &vert[..])?;
-    let frag_shader_module = create_shader_module(device, &frag[..])?;
-    
-    let vert_stage = vk::PipelineShaderStageCreateInfo::builder()
-        .stage(vk::ShaderStageFlags::VERTEX)
-        .module(vert_shader_module)
-        .name(b"main\0");
-
-    let frag_stage = vk::PipelineShaderStageCreateInfo::builder()
-        .stage(vk::ShaderStageFlags::FRAGMENT)
-        .module(frag_shader_module)
-        .name(b"main\0");
-
-
-    let binding_descriptions = &[Vertex::binding_description()];
-    let attribute_descriptions = Vertex::attribute_descriptions();
-    let vertex_input_state = vk::PipelineVertexInputStateCreateInfo::builder()
-        .vertex_binding_descriptions(binding_descriptions)
-        .vertex_attribute_descriptions(&attribute_descriptions);
-
-    let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
-        .topology(vk::PrimitiveTopology::TRIANGLE_LIST)
-        .primitive_restart_enable(false);
+use crate::reflect::{reflect_shader, ShaderReflection};
+use anyhow::{anyhow, Result};
 
-    let viewport = vk::Viewport::builder()
-        .x(0.0)
-        .y(0.0)
-        .width(data.swapchain_extent.width as f32)
-        .height(data.swapchain_extent.height as f32)
-        .min_depth(0.0)
-        .max_depth(1.0);
 
-    let scissor = vk::Rect2D::builder()
-        .offset(vk::Offset2D { x: 0, y: 0 })
-        .extent(data.swapchain_extent);
+/// The SPIR-V compiled by `build.rs`, used until a shader is hot reloaded.
+const VERT_SHADER: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/shader.vert.spv"));
+const FRAG_SHADER: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/shader.frag.spv"));
 
-    let viewports = &[viewport];
-    let scissors = &[scissor];
-    let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
-        .viewports(viewports)
-        .scissors(scissors);
 
-    let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
-        .depth_clamp_enable(false)
-        .rasterizer_discard_enable(false)
-        .polygon_mode(vk::PolygonMode::FILL)
-        .line_width(1.0)
-        .cull_mode(vk::CullModeFlags::BACK)
-        .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
-        .depth_bias_enable(false);
+/// Drawing in wireframe needs non-solid fill modes, which are used where supported.
+pub fn declare_requirements(data: &AppData, requirements: &mut DeviceRequirements) {
+    requirements.request_feature(Feature::FillModeNonSolid);
+}
 
-    let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
-        .sample_shading_enable(false)
-        .rasterization_samples(vk::SampleCountFlags::_1);
-        
+pub fn load_shaders(data: &mut AppData) -> Result<()> {
+    data.vert_shader = VERT_SHADER.to_vec();
+    data.frag_shader = FRAG_SHADER.to_vec();
+    data.shader_reflection = reflect_shaders(&data.vert_shader, &data.frag_shader)?;
 
-    let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfo::builder()
-        .depth_test_enable(true)
-        .depth_write_enable(true)
-        .depth_compare_op(vk::CompareOp::LESS)
-        .depth_bounds_test_enable(false)
-        .stencil_test_enable(false);
+    Ok(())
+}
 
+/// Reflects the pipeline interface shared by a vertex and fragment shader.
+pub fn reflect_shaders(vert: &[u8], frag: &[u8]) -> Result<ShaderReflection> {
+    let reflection = ShaderReflection::merge(&[reflect_shader(vert)?, reflect_shader(frag)?])?;
 
+    // Mismatches against the vertex layout are reported here instead of at draw time.
+    reflection.vertex_attributes(&Vertex::attribute_descriptions())?;
 
+    let push_constants_size = reflection.push_constant_ranges
+        .iter()
+        .map(|r| r.offset + r.size)
+        .max()
+        .unwrap_or(0) as usize;
 
+    if push_constants_size != size_of::<PushConstants>() {
+        return Err(anyhow!(
+            "Shaders declare {} bytes of push constants but {} bytes are pushed.",
+            push_constants_size,
+            size_of::<PushConstants>(),
+        ));
+    }
 
+    Ok(reflection)
+}
 
+/// Creates the layout shared by every pipeline, from the descriptor set layouts and push constant ranges.
+pub unsafe fn create_pipeline_layout(device: &SharedDevice, data: &mut AppData) -> Result<()> {
+    let set_layouts = data.descriptor_set_layouts.iter().map(|l| **l).collect::<Vec<_>>();
+    let push_constant_ranges = &data.shader_reflection.push_constant_ranges;
+    let layout_info = vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(&set_layouts)
+            .push_constant_ranges(push_constant_ranges);
 
-    let attachment = vk::PipelineColorBlendAttachmentState::builder()
-        .color_write_mask(vk::ColorComponentFlags::all())
-        .blend_enable(false);
+    data.pipeline_layout = data.descriptor_set_layouts.iter().fold(
+        Owned::new(device, device.create_pipeline_layout(&layout_info, None)?),
+        |layout, set_layout| layout.depends_on(set_layout),
+    );
 
+    Ok(())
+}
 
-    let attachments = &[attachment];
-    let color_blend_state = vk::PipelineColorBlendStateCreateInfo::builder()
-        .logic_op_enable(false)
-        .logic_op(vk::LogicOp::COPY)
-        .attachments(attachments)
-        .blend_constants([0.0, 0.0, 0.0, 0.0]);
+/// Gets the opaque and transparent scene pipelines for the current shaders and render pass.
+pub unsafe fn create_pipeline(device: &SharedDevice, data: &mut AppData) -> Result<()> {
+    let attribute_descriptions = data.shader_reflection
+        .vertex_attributes(&Vertex::attribute_descriptions())?;
 
+    let desc = PipelineDesc::new()
+        .stage(ShaderStage::new(vk::ShaderStageFlags::VERTEX, &data.vert_shader))
+        .stage(ShaderStage::new(vk::ShaderStageFlags::FRAGMENT, &data.frag_shader))
+        .vertex_input(&[Vertex::binding_description()], &attribute_descriptions)
+        .samples(data.msaa_samples)
+        .sample_shading(data.min_sample_shading)
+        .polygon_mode(if data.wireframe { vk::PolygonMode::LINE } else { vk::PolygonMode::FILL });
 
+    let opaque = desc.clone().blend(BlendMode::Opaque);
+    let transparent = desc.blend(BlendMode::Alpha).depth_write(false);
 
-    let dynamic_states = &[
-        vk::DynamicState::VIEWPORT,
-        vk::DynamicState::LINE_WIDTH,
-    ];
-    
-    let dynamic_state = vk::PipelineDynamicStateCreateInfo::builder()
-        .dynamic_states(dynamic_states);
+    let target = data.render_graph.target(SCENE_PASS)?;
+    let pipeline = data.pipelines.get(device, *data.pipeline_cache, &opaque, &data.pipeline_layout, target)?;
+    let transparent_pipeline = data.pipelines.get(device, *data.pipeline_cache, &transparent, &data.pipeline_layout, target)?;
 
-    let set_layouts = &[data.descriptor_set_layout];
-    let layout_info = vk::PipelineLayoutCreateInfo::builder()
-            .set_layouts(set_layouts);
-        
-    data.pipeline_layout = device.create_pipeline_layout(&layout_info, None)?;
-
-    let stages = &[vert_stage, frag_stage];
-    let info = vk::GraphicsPipelineCreateInfo::builder()
-        .stages(stages)
-        .vertex_input_state(&vertex_input_state)
-        .input_assembly_state(&input_assembly_state)
-        .viewport_state(&viewport_state)
-        .rasterization_state(&rasterization_state)
-        .multisample_state(&multisample_state)
-        .depth_stencil_state(&depth_stencil_state)
-        .color_blend_state(&color_blend_state)
-        .layout(data.pipeline_layout)
-        .render_pass(data.render_pass)
-        .subpass(0);
-
-
-    data.pipeline = device.create_graphics_pipelines(
-        vk::PipelineCache::null(), &[info], None)?.0[0];
-
-    device.destroy_shader_module(vert_shader_module, None);
-    device.destroy_shader_module(frag_shader_module, None);
+    data.pipeline = pipeline;
+    data.transparent_pipeline = transparent_pipeline;
 
     Ok(())
 }
@@ -0,0 +1,106 @@
+//! A pipeline cache that is loaded from and saved to disk so pipelines compile faster across runs.
+
+use std::fs;
+
+use anyhow::{anyhow, Result};
+use log::*;
+use vulkanalia::prelude::v1_0::*;
+
+use crate::app_data::AppData;
+use crate::owned::{Owned, SharedDevice};
+
+
+/// The size of `VkPipelineCacheHeaderVersionOne`.
+const HEADER_SIZE: usize = 32;
+
+/// Creates the pipeline cache, seeded with the cache file if it was written by this device and driver.
+pub unsafe fn create_pipeline_cache(instance: &Instance, device: &SharedDevice, data: &mut AppData) -> Result<()> {
+    let path = &data.settings.pipeline_cache;
+
+    let initial_data = match fs::read(path) {
+        Ok(bytes) => match check_header(instance, data, &bytes) {
+            Ok(()) => {
+                info!("Loaded pipeline cache `{}` ({} bytes).", path.display(), bytes.len());
+                bytes
+            }
+            Err(e) => {
+                warn!("Discarding pipeline cache `{}`: {}", path.display(), e);
+                vec![]
+            }
+        },
+        Err(e) => {
+            debug!("No pipeline cache loaded from `{}`: {}", path.display(), e);
+            vec![]
+        }
+    };
+
+    let info = vk::PipelineCacheCreateInfo::builder()
+        .initial_data(&initial_data);
+
+    // Drivers may still reject data that passes the header check, so fall back to an empty cache.
+    let cache = match device.create_pipeline_cache(&info, None) {
+        Ok(cache) => cache,
+        Err(e) if !initial_data.is_empty() => {
+            warn!("Discarding pipeline cache `{}`: {}", path.display(), e);
+            device.create_pipeline_cache(&vk::PipelineCacheCreateInfo::default(), None)?
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    data.pipeline_cache = Owned::new(device, cache);
+
+    Ok(())
+}
+
+/// Writes the pipeline cache to the cache file.
+///
+/// The file is replaced atomically so an interrupted write can't leave a truncated cache behind.
+pub unsafe fn save_pipeline_cache(device: &Device, data: &AppData) -> Result<()> {
+    let bytes = device.get_pipeline_cache_data(*data.pipeline_cache)?;
+
+    let path = &data.settings.pipeline_cache;
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, &bytes)?;
+    fs::rename(&temporary, path)?;
+
+    info!("Saved pipeline cache `{}` ({} bytes).", path.display(), bytes.len());
+
+    Ok(())
+}
+
+/// Checks that cache data has a version one header matching the physical device.
+unsafe fn check_header(instance: &Instance, data: &AppData, bytes: &[u8]) -> Result<()> {
+    if bytes.len() < HEADER_SIZE {
+        return Err(anyhow!("The file is too small ({} bytes).", bytes.len()));
+    }
+
+    // The header is written in the host's byte order.
+    let word = |index: usize| u32::from_ne_bytes(bytes[index * 4..index * 4 + 4].try_into().unwrap());
+    let (header_size, header_version, vendor_id, device_id) = (word(0), word(1), word(2), word(3));
+    let uuid = &bytes[16..HEADER_SIZE];
+
+    if (header_size as usize) < HEADER_SIZE || header_size as usize > bytes.len() {
+        return Err(anyhow!("Invalid header size {}.", header_size));
+    }
+
+    if header_version != vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32 {
+        return Err(anyhow!("Unsupported header version {}.", header_version));
+    }
+
+    let properties = instance.get_physical_device_properties(data.physical_device);
+    if vendor_id != properties.vendor_id || device_id != properties.device_id {
+        return Err(anyhow!(
+            "It was written by device {:04x}:{:04x}, not {:04x}:{:04x}.",
+            vendor_id,
+            device_id,
+            properties.vendor_id,
+            properties.device_id,
+        ));
+    }
+
+    if uuid != &properties.pipeline_cache_uuid[..] {
+        return Err(anyhow!("It was written by a different driver version."));
+    }
+
+    Ok(())
+}
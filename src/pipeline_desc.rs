@@ -0,0 +1,426 @@
+//! Descriptions of graphics pipelines, and a cache of the pipelines created from them.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+use log::*;
+use vulkanalia::prelude::v1_0::*;
+
+use crate::owned::{Owned, SharedDevice};
+use crate::render_graph::PassTarget;
+use crate::shader::create_shader_module;
+
+
+/// A shader and the values of its specialization constants.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct ShaderStage {
+    stage: vk::ShaderStageFlags,
+    code: Vec<u8>,
+    /// Constant IDs and their values.
+    constants: Vec<(u32, u32)>,
+}
+
+impl ShaderStage {
+    /// A stage running the `main` function of SPIR-V bytecode.
+    pub fn new(stage: vk::ShaderStageFlags, code: &[u8]) -> Self {
+        Self { stage, code: code.to_vec(), constants: vec![] }
+    }
+
+    /// Sets a 32-bit specialization constant (`bool`s are 32 bits and `f32`s can be passed with `to_bits`).
+    pub fn constant(mut self, id: u32, value: u32) -> Self {
+        self.constants.retain(|(i, _)| *i != id);
+        self.constants.push((id, value));
+        self
+    }
+}
+
+impl fmt::Debug for ShaderStage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ShaderStage")
+            .field("stage", &self.stage)
+            .field("code", &format_args!("{} bytes", self.code.len()))
+            .field("constants", &self.constants)
+            .finish()
+    }
+}
+
+/// How a color attachment is blended with what is already in it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum BlendMode {
+    Opaque,
+    Alpha,
+    PremultipliedAlpha,
+    Additive,
+}
+
+impl BlendMode {
+    fn state(self) -> vk::PipelineColorBlendAttachmentState {
+        let (src, dst) = match self {
+            BlendMode::Opaque => (vk::BlendFactor::ONE, vk::BlendFactor::ZERO),
+            BlendMode::Alpha => (vk::BlendFactor::SRC_ALPHA, vk::BlendFactor::ONE_MINUS_SRC_ALPHA),
+            BlendMode::PremultipliedAlpha => (vk::BlendFactor::ONE, vk::BlendFactor::ONE_MINUS_SRC_ALPHA),
+            BlendMode::Additive => (vk::BlendFactor::ONE, vk::BlendFactor::ONE),
+        };
+
+        vk::PipelineColorBlendAttachmentState::builder()
+            .color_write_mask(vk::ColorComponentFlags::all())
+            .blend_enable(self != BlendMode::Opaque)
+            .src_color_blend_factor(src)
+            .dst_color_blend_factor(dst)
+            .color_blend_op(vk::BlendOp::ADD)
+            .src_alpha_blend_factor(vk::BlendFactor::ONE)
+            .dst_alpha_blend_factor(dst)
+            .alpha_blend_op(vk::BlendOp::ADD)
+            .build()
+    }
+}
+
+/// The shaders and fixed-function state of a graphics pipeline.
+///
+/// Viewports and scissors are always dynamic, so the same pipeline can
+/// be used at any resolution.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PipelineDesc {
+    stages: Vec<ShaderStage>,
+    vertex_bindings: Vec<vk::VertexInputBindingDescription>,
+    vertex_attributes: Vec<vk::VertexInputAttributeDescription>,
+    topology: vk::PrimitiveTopology,
+    polygon_mode: vk::PolygonMode,
+    cull_mode: vk::CullModeFlags,
+    front_face: vk::FrontFace,
+    depth_compare: Option<vk::CompareOp>,
+    depth_write: bool,
+    stencil: Option<(vk::StencilOpState, vk::StencilOpState)>,
+    blend: Vec<BlendMode>,
+    samples: vk::SampleCountFlags,
+    /// The bits of the minimum sample shading fraction, since `f32` isn't `Hash`.
+    min_sample_shading: Option<u32>,
+    dynamic_states: Vec<vk::DynamicState>,
+    subpass: u32,
+}
+
+impl Default for PipelineDesc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PipelineDesc {
+    /// Filled triangle lists with counter-clockwise, back-face culled
+    /// triangles, a `LESS` depth test and a single sample.
+    ///
+    /// There are no color attachments until [`PipelineDesc::blend`] is called.
+    pub fn new() -> Self {
+        Self {
+            stages: vec![],
+            vertex_bindings: vec![],
+            vertex_attributes: vec![],
+            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+            polygon_mode: vk::PolygonMode::FILL,
+            cull_mode: vk::CullModeFlags::BACK,
+            front_face: vk::FrontFace::COUNTER_CLOCKWISE,
+            depth_compare: Some(vk::CompareOp::LESS),
+            depth_write: true,
+            stencil: None,
+            blend: vec![],
+            samples: vk::SampleCountFlags::_1,
+            min_sample_shading: None,
+            dynamic_states: vec![vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR],
+            subpass: 0,
+        }
+    }
+
+    pub fn stage(mut self, stage: ShaderStage) -> Self {
+        self.stages.push(stage);
+        self
+    }
+
+    pub fn vertex_input(
+        mut self,
+        bindings: &[vk::VertexInputBindingDescription],
+        attributes: &[vk::VertexInputAttributeDescription],
+    ) -> Self {
+        self.vertex_bindings = bindings.to_vec();
+        self.vertex_attributes = attributes.to_vec();
+        self
+    }
+
+    pub fn topology(mut self, topology: vk::PrimitiveTopology) -> Self {
+        self.topology = topology;
+        self
+    }
+
+    pub fn polygon_mode(mut self, polygon_mode: vk::PolygonMode) -> Self {
+        self.polygon_mode = polygon_mode;
+        self
+    }
+
+    pub fn cull_mode(mut self, cull_mode: vk::CullModeFlags) -> Self {
+        self.cull_mode = cull_mode;
+        self
+    }
+
+    pub fn front_face(mut self, front_face: vk::FrontFace) -> Self {
+        self.front_face = front_face;
+        self
+    }
+
+    /// Sets the depth test, or disables it with `None`.
+    pub fn depth_test(mut self, compare: Option<vk::CompareOp>) -> Self {
+        self.depth_compare = compare;
+        self
+    }
+
+    pub fn depth_write(mut self, write: bool) -> Self {
+        self.depth_write = write;
+        self
+    }
+
+    /// Enables the stencil test with the operations for front and back faces.
+    pub fn stencil(mut self, front: vk::StencilOpState, back: vk::StencilOpState) -> Self {
+        self.stencil = Some((front, back));
+        self
+    }
+
+    /// Adds a color attachment blended with `mode`, in attachment order.
+    pub fn blend(mut self, mode: BlendMode) -> Self {
+        self.blend.push(mode);
+        self
+    }
+
+    pub fn samples(mut self, samples: vk::SampleCountFlags) -> Self {
+        self.samples = samples;
+        self
+    }
+
+    /// Shades at least `min` of the samples of each pixel separately.
+    ///
+    /// Requires the `sample_rate_shading` device feature.
+    pub fn sample_shading(mut self, min: Option<f32>) -> Self {
+        self.min_sample_shading = min.map(f32::to_bits);
+        self
+    }
+
+    /// Adds a dynamic state in addition to the viewport and scissor.
+    pub fn dynamic_state(mut self, state: vk::DynamicState) -> Self {
+        if !self.dynamic_states.contains(&state) {
+            self.dynamic_states.push(state);
+        }
+
+        self
+    }
+
+    pub fn subpass(mut self, subpass: u32) -> Self {
+        self.subpass = subpass;
+        self
+    }
+
+    /// Creates a pipeline from this description.
+    pub unsafe fn create(
+        &self,
+        device: &SharedDevice,
+        cache: vk::PipelineCache,
+        layout: &Owned<vk::PipelineLayout>,
+        target: &PassTarget,
+    ) -> Result<Owned<vk::Pipeline>> {
+        if !self.stages.iter().any(|s| s.stage == vk::ShaderStageFlags::VERTEX) {
+            return Err(anyhow!("A graphics pipeline needs a vertex shader."));
+        }
+
+        // Modules are only needed until the pipeline has been created.
+        let modules = self.stages
+            .iter()
+            .map(|s| create_shader_module(device, &s.code))
+            .collect::<Result<Vec<_>>>()?;
+
+        let map_entries = self.stages
+            .iter()
+            .map(|s| {
+                (0..s.constants.len())
+                    .map(|i| vk::SpecializationMapEntry { constant_id: s.constants[i].0, offset: 4 * i as u32, size: 4 })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let constant_data = self.stages
+            .iter()
+            .map(|s| s.constants.iter().flat_map(|(_, v)| v.to_ne_bytes()).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        let specialization_infos = map_entries
+            .iter()
+            .zip(&constant_data)
+            .map(|(entries, data)| vk::SpecializationInfo::builder().map_entries(entries).data(data))
+            .collect::<Vec<_>>();
+
+        let stages = self.stages
+            .iter()
+            .zip(&modules)
+            .zip(&specialization_infos)
+            .map(|((stage, module), specialization_info)| {
+                let info = vk::PipelineShaderStageCreateInfo::builder()
+                    .stage(stage.stage)
+                    .module(**module)
+                    .name(b"main\0");
+
+                if stage.constants.is_empty() {
+                    info
+                } else {
+                    info.specialization_info(specialization_info)
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let vertex_input_state = vk::PipelineVertexInputStateCreateInfo::builder()
+            .vertex_binding_descriptions(&self.vertex_bindings)
+            .vertex_attribute_descriptions(&self.vertex_attributes);
+
+        let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(self.topology)
+            .primitive_restart_enable(false);
+
+        // The viewport and scissor are always dynamic, so only their counts are needed here.
+        let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+
+        let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
+            .depth_clamp_enable(false)
+            .rasterizer_discard_enable(false)
+            .polygon_mode(self.polygon_mode)
+            .line_width(1.0)
+            .cull_mode(self.cull_mode)
+            .front_face(self.front_face)
+            .depth_bias_enable(false);
+
+        let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
+            .sample_shading_enable(self.min_sample_shading.is_some())
+            .min_sample_shading(self.min_sample_shading.map_or(0.0, f32::from_bits))
+            .rasterization_samples(self.samples);
+
+        let (front, back) = self.stencil.unwrap_or_default();
+        let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfo::builder()
+            .depth_test_enable(self.depth_compare.is_some())
+            .depth_write_enable(self.depth_write)
+            .depth_compare_op(self.depth_compare.unwrap_or(vk::CompareOp::ALWAYS))
+            .depth_bounds_test_enable(false)
+            .stencil_test_enable(self.stencil.is_some())
+            .front(front)
+            .back(back);
+
+        let attachments = self.blend.iter().map(|b| b.state()).collect::<Vec<_>>();
+        let color_blend_state = vk::PipelineColorBlendStateCreateInfo::builder()
+            .logic_op_enable(false)
+            .logic_op(vk::LogicOp::COPY)
+            .attachments(&attachments)
+            .blend_constants([0.0, 0.0, 0.0, 0.0]);
+
+        let dynamic_state = vk::PipelineDynamicStateCreateInfo::builder()
+            .dynamic_states(&self.dynamic_states);
+
+        let mut info = vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&stages)
+            .vertex_input_state(&vertex_input_state)
+            .input_assembly_state(&input_assembly_state)
+            .viewport_state(&viewport_state)
+            .rasterization_state(&rasterization_state)
+            .multisample_state(&multisample_state)
+            .depth_stencil_state(&depth_stencil_state)
+            .color_blend_state(&color_blend_state)
+            .dynamic_state(&dynamic_state)
+            .layout(**layout);
+
+        let mut rendering_info = vk::PipelineRenderingCreateInfo::builder();
+        match target {
+            PassTarget::RenderPass(render_pass) => {
+                info = info.render_pass(**render_pass).subpass(self.subpass);
+            }
+            PassTarget::Dynamic { color_formats, depth_format, stencil_format } => {
+                rendering_info = rendering_info
+                    .color_attachment_formats(color_formats)
+                    .depth_attachment_format(*depth_format)
+                    .stencil_attachment_format(*stencil_format);
+                info = info.push_next(&mut rendering_info);
+            }
+        }
+
+        let pipeline = Owned::new(device, device.create_graphics_pipelines(cache, &[info], None)?.0[0])
+            .depends_on(layout);
+
+        Ok(match target {
+            PassTarget::RenderPass(render_pass) => pipeline.depends_on(render_pass),
+            PassTarget::Dynamic { .. } => pipeline,
+        })
+    }
+}
+
+/// Identifies what a pipeline was created for in [`GraphicsPipelines`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum TargetKey {
+    RenderPass(vk::RenderPass),
+    Dynamic(Vec<vk::Format>, vk::Format, vk::Format),
+}
+
+impl From<&PassTarget> for TargetKey {
+    fn from(target: &PassTarget) -> Self {
+        match target {
+            PassTarget::RenderPass(render_pass) => Self::RenderPass(**render_pass),
+            PassTarget::Dynamic { color_formats, depth_format, stencil_format } =>
+                Self::Dynamic(color_formats.clone(), *depth_format, *stencil_format),
+        }
+    }
+}
+
+/// The pipelines created so far, keyed by their description, layout and target.
+#[derive(Clone, Debug, Default)]
+pub struct GraphicsPipelines {
+    pipelines: HashMap<(PipelineDesc, vk::PipelineLayout, TargetKey), Owned<vk::Pipeline>>,
+}
+
+impl GraphicsPipelines {
+    /// Gets the pipeline for a description, creating it the first time it is used.
+    pub unsafe fn get(
+        &mut self,
+        device: &SharedDevice,
+        cache: vk::PipelineCache,
+        desc: &PipelineDesc,
+        layout: &Owned<vk::PipelineLayout>,
+        target: &PassTarget,
+    ) -> Result<Owned<vk::Pipeline>> {
+        let key = (desc.clone(), **layout, TargetKey::from(target));
+        if let Some(pipeline) = self.pipelines.get(&key) {
+            return Ok(pipeline.clone());
+        }
+
+        let pipeline = desc.create(device, cache, layout, target)?;
+        self.pipelines.insert(key, pipeline.clone());
+        debug!("Created pipeline {} ({} cached).", pipeline.as_raw(), self.pipelines.len());
+
+        Ok(pipeline)
+    }
+
+    /// Removes the pipelines with a stage running any of `code`, e.g., once a
+    /// shader has been reloaded, returning them so they can be kept alive
+    /// until frames using them have completed.
+    pub fn evict_shaders(&mut self, code: &[&[u8]]) -> Vec<Owned<vk::Pipeline>> {
+        let mut evicted = vec![];
+        self.pipelines.retain(|(desc, _, _), pipeline| {
+            let stale = desc.stages.iter().any(|s| code.contains(&s.code.as_slice()));
+            if stale {
+                evicted.push(pipeline.clone());
+            }
+
+            !stale
+        });
+
+        evicted
+    }
+
+    /// Forgets every pipeline, e.g., once the render passes they were created for are rebuilt.
+    ///
+    /// Pipelines still in use elsewhere are destroyed once they are dropped there.
+    pub fn clear(&mut self) {
+        self.pipelines.clear();
+    }
+}
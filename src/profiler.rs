@@ -0,0 +1,233 @@
+//! Measures how long each pass takes on the GPU with timestamp queries.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use log::*;
+use serde::Serialize;
+use vulkanalia::prelude::v1_0::*;
+
+use crate::app_data::AppData;
+use crate::owned::{Owned, SharedDevice};
+
+
+/// The scope covering every pass of a frame, followed by a scope per pass.
+pub const FRAME_SCOPE: usize = 0;
+
+/// How many frames the averages are taken over.
+const ROLLING_FRAMES: usize = 120;
+
+/// How often the averages are logged and written out.
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+
+/// The timings of a scope, as written to the profile.
+#[derive(Clone, Debug, Serialize)]
+struct ScopeReport {
+    name: &'static str,
+    average_ms: f64,
+    min_ms: f64,
+    max_ms: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct Report {
+    frames: usize,
+    scopes: Vec<ScopeReport>,
+}
+
+/// Writes a timestamp at the start and end of each scope of a frame and
+/// reads them back once the frame has completed, keeping rolling averages.
+///
+/// The default value profiles nothing and records no commands.
+#[derive(Clone, Debug)]
+pub struct GpuProfiler {
+    /// One per frame in flight, with a pair of queries per scope.
+    query_pools: Vec<Owned<vk::QueryPool>>,
+    /// Whether each frame in flight has written timestamps that haven't been read.
+    pending: Vec<bool>,
+    scopes: Vec<&'static str>,
+    /// The nanoseconds per timestamp tick.
+    timestamp_period: f64,
+    /// The bits of a timestamp that are valid.
+    timestamp_mask: u64,
+    /// The last [`ROLLING_FRAMES`] durations of each scope, in milliseconds.
+    samples: Vec<VecDeque<f64>>,
+    output: PathBuf,
+    since: Instant,
+}
+
+impl Default for GpuProfiler {
+    fn default() -> Self {
+        Self {
+            query_pools: vec![],
+            pending: vec![],
+            scopes: vec![],
+            timestamp_period: 0.0,
+            timestamp_mask: 0,
+            samples: vec![],
+            output: PathBuf::new(),
+            since: Instant::now(),
+        }
+    }
+}
+
+/// Creates the profiler if the `profile` setting is set and the graphics queue can write timestamps.
+///
+/// The render graph must have been created, since there is a scope for each of its passes.
+pub unsafe fn create_profiler(instance: &Instance, device: &SharedDevice, data: &mut AppData) -> Result<()> {
+    let Some(output) = data.settings.profile.clone() else {
+        return Ok(());
+    };
+
+    // Commands are recorded for the graphics queue, so only its family matters.
+    let valid_bits = instance
+        .get_physical_device_queue_family_properties(data.physical_device)[data.queue_families.graphics as usize]
+        .timestamp_valid_bits;
+
+    if valid_bits == 0 {
+        warn!("GPU profiling is disabled since the graphics queue doesn't support timestamps.");
+        return Ok(());
+    }
+
+    let properties = instance.get_physical_device_properties(data.physical_device);
+
+    let scopes = std::iter::once("frame").chain(data.render_graph.pass_names()).collect::<Vec<_>>();
+    let info = vk::QueryPoolCreateInfo::builder()
+        .query_type(vk::QueryType::TIMESTAMP)
+        .query_count(2 * scopes.len() as u32);
+
+    let query_pools = (0..data.settings.frames_in_flight)
+        .map(|_| Ok(Owned::new(device, device.create_query_pool(&info, None)?)))
+        .collect::<Result<Vec<_>>>()?;
+
+    info!("Profiling {} GPU scope(s), writing averages to `{}`.", scopes.len(), output.display());
+
+    data.profiler = GpuProfiler {
+        pending: vec![false; query_pools.len()],
+        query_pools,
+        samples: vec![VecDeque::with_capacity(ROLLING_FRAMES); scopes.len()],
+        scopes,
+        timestamp_period: properties.limits.timestamp_period as f64,
+        timestamp_mask: if valid_bits >= 64 { u64::MAX } else { (1 << valid_bits) - 1 },
+        output,
+        since: Instant::now(),
+    };
+
+    Ok(())
+}
+
+impl GpuProfiler {
+    pub fn is_enabled(&self) -> bool {
+        !self.query_pools.is_empty()
+    }
+
+    /// Resets the queries of a frame in flight and begins the frame scope.
+    ///
+    /// Must be recorded before any render pass begins.
+    pub unsafe fn begin_frame(&self, device: &Device, command_buffer: vk::CommandBuffer, frame: usize) {
+        if let Some(pool) = self.query_pools.get(frame) {
+            device.cmd_reset_query_pool(command_buffer, **pool, 0, 2 * self.scopes.len() as u32);
+            self.begin_scope(device, command_buffer, frame, FRAME_SCOPE);
+        }
+    }
+
+    pub unsafe fn begin_scope(&self, device: &Device, command_buffer: vk::CommandBuffer, frame: usize, scope: usize) {
+        if let Some(pool) = self.query_pools.get(frame) {
+            let stage = vk::PipelineStageFlags::TOP_OF_PIPE;
+            device.cmd_write_timestamp(command_buffer, stage, **pool, 2 * scope as u32);
+        }
+    }
+
+    pub unsafe fn end_scope(&self, device: &Device, command_buffer: vk::CommandBuffer, frame: usize, scope: usize) {
+        if let Some(pool) = self.query_pools.get(frame) {
+            let stage = vk::PipelineStageFlags::BOTTOM_OF_PIPE;
+            device.cmd_write_timestamp(command_buffer, stage, **pool, 2 * scope as u32 + 1);
+        }
+    }
+
+    /// Notes that a frame in flight has been submitted with timestamps to read.
+    pub fn frame_submitted(&mut self, frame: usize) {
+        if let Some(pending) = self.pending.get_mut(frame) {
+            *pending = true;
+        }
+    }
+
+    /// Reads the timestamps of a frame in flight whose submission has
+    /// completed, reporting the averages every few seconds.
+    pub unsafe fn read_frame(&mut self, device: &Device, frame: usize) -> Result<()> {
+        if !self.pending.get(frame).copied().unwrap_or(false) {
+            return Ok(());
+        }
+
+        self.pending[frame] = false;
+
+        let mut timestamps = vec![0u64; 2 * self.scopes.len()];
+        let result = device.get_query_pool_results(
+            *self.query_pools[frame],
+            0,
+            timestamps.len() as u32,
+            std::slice::from_raw_parts_mut(timestamps.as_mut_ptr().cast(), 8 * timestamps.len()),
+            8,
+            vk::QueryResultFlags::_64,
+        )?;
+
+        // The frame has completed, so this only happens if it was never recorded.
+        if result == vk::SuccessCode::NOT_READY {
+            return Ok(());
+        }
+
+        for (samples, pair) in self.samples.iter_mut().zip(timestamps.chunks_exact(2)) {
+            let ticks = pair[1].wrapping_sub(pair[0]) & self.timestamp_mask;
+            if samples.len() == ROLLING_FRAMES {
+                samples.pop_front();
+            }
+
+            samples.push_back(ticks as f64 * self.timestamp_period / 1_000_000.0);
+        }
+
+        if self.since.elapsed() >= REPORT_INTERVAL {
+            self.report()?;
+            self.since = Instant::now();
+        }
+
+        Ok(())
+    }
+
+    /// Logs the average time of each scope and writes them to the output file.
+    pub fn report(&self) -> Result<()> {
+        if !self.is_enabled() || self.samples[FRAME_SCOPE].is_empty() {
+            return Ok(());
+        }
+
+        let scopes = self.scopes
+            .iter()
+            .zip(&self.samples)
+            .map(|(name, samples)| ScopeReport {
+                name,
+                average_ms: samples.iter().sum::<f64>() / samples.len() as f64,
+                min_ms: samples.iter().cloned().fold(f64::INFINITY, f64::min),
+                max_ms: samples.iter().cloned().fold(0.0, f64::max),
+            })
+            .collect::<Vec<_>>();
+
+        let report = Report { frames: self.samples[FRAME_SCOPE].len(), scopes };
+        for scope in &report.scopes {
+            info!(
+                "GPU `{}`: {:.3} ms on average over {} frame(s) ({:.3} to {:.3} ms).",
+                scope.name,
+                scope.average_ms,
+                report.frames,
+                scope.min_ms,
+                scope.max_ms,
+            );
+        }
+
+        let json = serde_json::to_string_pretty(&report)?;
+        fs::write(&self.output, json)
+            .map_err(|e| anyhow!("Failed to write profile `{}`: {}", self.output.display(), e))
+    }
+}
@@ -1,6 +1,6 @@
 use anyhow::{anyhow, Result};
 
-use vulkanalia::vk::{self, InstanceV1_0, KhrSurfaceExtension};
+use vulkanalia::vk::{self, Handle, InstanceV1_0, KhrSurfaceExtension};
 use vulkanalia::Instance;
 
 
@@ -11,9 +11,11 @@ use crate::app_data::AppData;
 
 
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Default)]
 pub(crate) struct QueueFamilyIndices {
     pub(crate) graphics: u32,
+    /// A family dedicated to transfers when there is one, so uploads can run
+    /// alongside rendering. Otherwise the graphics family.
     pub(crate) transfer: u32,
     pub(crate) present: u32,
 }
@@ -32,21 +34,37 @@ pub(crate) struct QueueFamilyIndices {
             .map(|i| i as u32);
 
 
+        // Graphics families support transfers whether or not they report it.
+        let transfer_only = |p: &vk::QueueFamilyProperties| {
+            p.queue_flags.contains(vk::QueueFlags::TRANSFER)
+                && !p.queue_flags.intersects(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE)
+        };
+        let non_graphics = |p: &vk::QueueFamilyProperties| {
+            p.queue_flags.contains(vk::QueueFlags::TRANSFER) && !p.queue_flags.contains(vk::QueueFlags::GRAPHICS)
+        };
+
         let transfer = properties
             .iter()
-            .position(|p| p.queue_flags.contains(vk::QueueFlags::TRANSFER))
-            .map(|i| i as u32); 
+            .position(transfer_only)
+            .or_else(|| properties.iter().position(non_graphics))
+            .map(|i| i as u32)
+            .or(graphics);
 
 
         let mut present = None;
-        for (index, properties) in properties.iter().enumerate() {
-            if instance.get_physical_device_surface_support_khr(
-                physical_device,
-                index as u32,
-                data.surface,
-            )? {
-                present = Some(index as u32);
-                break;
+        if data.surface.is_null() {
+            // Without a surface nothing is presented, so any graphics queue will do.
+            present = graphics;
+        } else {
+            for (index, properties) in properties.iter().enumerate() {
+                if instance.get_physical_device_surface_support_khr(
+                    physical_device,
+                    index as u32,
+                    data.surface,
+                )? {
+                    present = Some(index as u32);
+                    break;
+                }
             }
         }
         if let (Some(graphics),Some(transfer), Some(present)) = (graphics, transfer, present) {
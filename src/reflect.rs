@@ -0,0 +1,431 @@
+//! Minimal SPIR-V reflection of the resources a shader module uses.
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use vulkanalia::vk;
+
+
+const MAGIC: u32 = 0x0723_0203;
+
+// Opcodes.
+const OP_ENTRY_POINT: u16 = 15;
+const OP_TYPE_BOOL: u16 = 20;
+const OP_TYPE_INT: u16 = 21;
+const OP_TYPE_FLOAT: u16 = 22;
+const OP_TYPE_VECTOR: u16 = 23;
+const OP_TYPE_MATRIX: u16 = 24;
+const OP_TYPE_IMAGE: u16 = 25;
+const OP_TYPE_SAMPLER: u16 = 26;
+const OP_TYPE_SAMPLED_IMAGE: u16 = 27;
+const OP_TYPE_ARRAY: u16 = 28;
+const OP_TYPE_RUNTIME_ARRAY: u16 = 29;
+const OP_TYPE_STRUCT: u16 = 30;
+const OP_TYPE_POINTER: u16 = 32;
+const OP_CONSTANT: u16 = 43;
+const OP_VARIABLE: u16 = 59;
+const OP_DECORATE: u16 = 71;
+const OP_MEMBER_DECORATE: u16 = 72;
+
+// Decorations.
+const DECORATION_BUFFER_BLOCK: u32 = 3;
+const DECORATION_ARRAY_STRIDE: u32 = 6;
+const DECORATION_MATRIX_STRIDE: u32 = 7;
+const DECORATION_BUILT_IN: u32 = 11;
+const DECORATION_LOCATION: u32 = 30;
+const DECORATION_BINDING: u32 = 33;
+const DECORATION_DESCRIPTOR_SET: u32 = 34;
+const DECORATION_OFFSET: u32 = 35;
+
+// Storage classes.
+const STORAGE_UNIFORM_CONSTANT: u32 = 0;
+const STORAGE_INPUT: u32 = 1;
+const STORAGE_UNIFORM: u32 = 2;
+const STORAGE_PUSH_CONSTANT: u32 = 9;
+const STORAGE_STORAGE_BUFFER: u32 = 12;
+
+// Image dimensions.
+const DIM_BUFFER: u32 = 5;
+const DIM_SUBPASS_DATA: u32 = 6;
+
+
+/// A descriptor binding used by one or more shader stages.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DescriptorBinding {
+    pub set: u32,
+    pub binding: u32,
+    pub type_: vk::DescriptorType,
+    pub count: u32,
+    pub stages: vk::ShaderStageFlags,
+    /// The size of the block for uniform and storage buffers.
+    pub size: u32,
+}
+
+/// A vertex shader input.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct VertexInput {
+    pub location: u32,
+    pub format: vk::Format,
+}
+
+/// The resources used by a shader module, or by all the modules of a pipeline once merged.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShaderReflection {
+    pub stages: vk::ShaderStageFlags,
+    pub bindings: Vec<DescriptorBinding>,
+    pub push_constant_ranges: Vec<vk::PushConstantRange>,
+    pub vertex_inputs: Vec<VertexInput>,
+}
+
+impl ShaderReflection {
+    /// Merges the reflection of several shader stages into one pipeline interface.
+    pub fn merge(reflections: &[ShaderReflection]) -> Result<Self> {
+        let mut merged = Self::default();
+
+        for reflection in reflections {
+            merged.stages |= reflection.stages;
+            merged.vertex_inputs.extend_from_slice(&reflection.vertex_inputs);
+            merged.push_constant_ranges.extend_from_slice(&reflection.push_constant_ranges);
+
+            for binding in &reflection.bindings {
+                match merged.bindings.iter_mut().find(|b| b.set == binding.set && b.binding == binding.binding) {
+                    Some(existing) if existing.type_ != binding.type_ || existing.count != binding.count => {
+                        return Err(anyhow!(
+                            "Descriptor (set = {}, binding = {}) is declared as {:?} x{} and {:?} x{} in different stages.",
+                            binding.set,
+                            binding.binding,
+                            existing.type_,
+                            existing.count,
+                            binding.type_,
+                            binding.count,
+                        ));
+                    }
+                    Some(existing) => {
+                        existing.stages |= binding.stages;
+                        existing.size = existing.size.max(binding.size);
+                    }
+                    None => merged.bindings.push(*binding),
+                }
+            }
+        }
+
+        merged.bindings.sort_by_key(|b| (b.set, b.binding));
+        merged.vertex_inputs.sort_by_key(|i| i.location);
+
+        Ok(merged)
+    }
+
+    /// Gets the descriptor pool sizes needed to allocate one descriptor set with the given set number.
+    pub fn pool_sizes(&self, set: u32) -> Vec<vk::DescriptorPoolSize> {
+        let mut sizes = Vec::<vk::DescriptorPoolSize>::new();
+        for binding in self.bindings.iter().filter(|b| b.set == set) {
+            match sizes.iter_mut().find(|s| s.type_ == binding.type_) {
+                Some(size) => size.descriptor_count += binding.count,
+                None => sizes.push(vk::DescriptorPoolSize { type_: binding.type_, descriptor_count: binding.count }),
+            }
+        }
+
+        sizes
+    }
+
+    /// Selects the vertex attributes consumed by the vertex shader.
+    ///
+    /// Every shader input must be provided by an attribute with the same
+    /// location and format; attributes the shader does not read are dropped.
+    pub fn vertex_attributes(
+        &self,
+        attributes: &[vk::VertexInputAttributeDescription],
+    ) -> Result<Vec<vk::VertexInputAttributeDescription>> {
+        self.vertex_inputs
+            .iter()
+            .map(|input| {
+                let attribute = attributes
+                    .iter()
+                    .find(|a| a.location == input.location)
+                    .ok_or_else(|| anyhow!("Vertex shader input at location {} has no vertex attribute.", input.location))?;
+
+                if attribute.format != input.format {
+                    return Err(anyhow!(
+                        "Vertex shader input at location {} is {:?} but the vertex attribute is {:?}.",
+                        input.location,
+                        input.format,
+                        attribute.format,
+                    ));
+                }
+
+                Ok(*attribute)
+            })
+            .collect()
+    }
+}
+
+
+#[derive(Clone, Debug)]
+enum Type {
+    Scalar { float: bool, signed: bool, width: u32 },
+    Vector { component: u32, count: u32 },
+    Matrix { column: u32, count: u32 },
+    Image { dim: u32, sampled: u32 },
+    Sampler,
+    SampledImage,
+    Array { element: u32, length: u32 },
+    RuntimeArray { element: u32 },
+    Struct { members: Vec<u32> },
+    Pointer { type_: u32 },
+}
+
+#[derive(Clone, Debug, Default)]
+struct Decorations {
+    set: Option<u32>,
+    binding: Option<u32>,
+    location: Option<u32>,
+    built_in: bool,
+    buffer_block: bool,
+    array_stride: Option<u32>,
+    member_offsets: HashMap<u32, u32>,
+    member_matrix_strides: HashMap<u32, u32>,
+}
+
+/// Reflects the descriptor bindings, push constants and vertex inputs of a SPIR-V module.
+pub fn reflect_shader(bytecode: &[u8]) -> Result<ShaderReflection> {
+    if !bytecode.len().is_multiple_of(4) || bytecode.len() < 20 {
+        return Err(anyhow!("SPIR-V bytecode has an invalid size ({} bytes).", bytecode.len()));
+    }
+
+    let words = bytecode
+        .chunks_exact(4)
+        .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+        .collect::<Vec<_>>();
+
+    if words[0] != MAGIC {
+        return Err(anyhow!("SPIR-V bytecode has an invalid magic number."));
+    }
+
+    let mut stages = vk::ShaderStageFlags::empty();
+    let mut types = HashMap::new();
+    let mut constants = HashMap::new();
+    let mut decorations = HashMap::<u32, Decorations>::new();
+    let mut variables = vec![];
+
+    let mut offset = 5;
+    while offset < words.len() {
+        let count = (words[offset] >> 16) as usize;
+        let opcode = (words[offset] & 0xFFFF) as u16;
+        if count == 0 || offset + count > words.len() {
+            return Err(anyhow!("SPIR-V bytecode has a truncated instruction."));
+        }
+
+        let operands = &words[offset + 1..offset + count];
+        offset += count;
+
+        match opcode {
+            OP_ENTRY_POINT => stages |= get_stage(operands[0])?,
+            OP_TYPE_BOOL => { types.insert(operands[0], Type::Scalar { float: false, signed: false, width: 32 }); }
+            OP_TYPE_INT => { types.insert(operands[0], Type::Scalar { float: false, signed: operands[2] != 0, width: operands[1] }); }
+            OP_TYPE_FLOAT => { types.insert(operands[0], Type::Scalar { float: true, signed: true, width: operands[1] }); }
+            OP_TYPE_VECTOR => { types.insert(operands[0], Type::Vector { component: operands[1], count: operands[2] }); }
+            OP_TYPE_MATRIX => { types.insert(operands[0], Type::Matrix { column: operands[1], count: operands[2] }); }
+            OP_TYPE_IMAGE => { types.insert(operands[0], Type::Image { dim: operands[2], sampled: operands[6] }); }
+            OP_TYPE_SAMPLER => { types.insert(operands[0], Type::Sampler); }
+            OP_TYPE_SAMPLED_IMAGE => { types.insert(operands[0], Type::SampledImage); }
+            OP_TYPE_ARRAY => { types.insert(operands[0], Type::Array { element: operands[1], length: operands[2] }); }
+            OP_TYPE_RUNTIME_ARRAY => { types.insert(operands[0], Type::RuntimeArray { element: operands[1] }); }
+            OP_TYPE_STRUCT => { types.insert(operands[0], Type::Struct { members: operands[1..].to_vec() }); }
+            OP_TYPE_POINTER => { types.insert(operands[0], Type::Pointer { type_: operands[2] }); }
+            OP_CONSTANT => { constants.insert(operands[1], operands[2]); }
+            OP_VARIABLE => variables.push((operands[0], operands[1], operands[2])),
+            OP_DECORATE => {
+                let decoration = decorations.entry(operands[0]).or_default();
+                match operands[1] {
+                    DECORATION_BUFFER_BLOCK => decoration.buffer_block = true,
+                    DECORATION_ARRAY_STRIDE => decoration.array_stride = Some(operands[2]),
+                    DECORATION_BUILT_IN => decoration.built_in = true,
+                    DECORATION_LOCATION => decoration.location = Some(operands[2]),
+                    DECORATION_BINDING => decoration.binding = Some(operands[2]),
+                    DECORATION_DESCRIPTOR_SET => decoration.set = Some(operands[2]),
+                    _ => {}
+                }
+            }
+            OP_MEMBER_DECORATE => {
+                let decoration = decorations.entry(operands[0]).or_default();
+                match operands[2] {
+                    DECORATION_OFFSET => { decoration.member_offsets.insert(operands[1], operands[3]); }
+                    DECORATION_MATRIX_STRIDE => { decoration.member_matrix_strides.insert(operands[1], operands[3]); }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let module = Module { types, constants, decorations };
+
+    let mut reflection = ShaderReflection { stages, ..Default::default() };
+    for (type_, id, storage_class) in variables {
+        let Some(Type::Pointer { type_ }) = module.types.get(&type_).cloned() else {
+            continue;
+        };
+
+        let decoration = module.decorations.get(&id).cloned().unwrap_or_default();
+        match storage_class {
+            STORAGE_UNIFORM_CONSTANT | STORAGE_UNIFORM | STORAGE_STORAGE_BUFFER => {
+                let (element, count) = module.get_array_element(type_);
+                let descriptor_type = module.get_descriptor_type(element, storage_class)?;
+                let size = match descriptor_type {
+                    vk::DescriptorType::UNIFORM_BUFFER | vk::DescriptorType::STORAGE_BUFFER => module.get_size(element, None)?,
+                    _ => 0,
+                };
+
+                reflection.bindings.push(DescriptorBinding {
+                    set: decoration.set.unwrap_or(0),
+                    binding: decoration.binding.unwrap_or(0),
+                    type_: descriptor_type,
+                    count,
+                    stages,
+                    size,
+                });
+            }
+            STORAGE_PUSH_CONSTANT => {
+                reflection.push_constant_ranges.push(vk::PushConstantRange {
+                    stage_flags: stages,
+                    offset: 0,
+                    size: module.get_size(type_, None)?,
+                });
+            }
+            STORAGE_INPUT if stages == vk::ShaderStageFlags::VERTEX && !decoration.built_in => {
+                // Built-in blocks such as `gl_PerVertex` are decorated per member instead.
+                let Some(location) = decoration.location else {
+                    continue;
+                };
+
+                reflection.vertex_inputs.push(VertexInput {
+                    location,
+                    format: module.get_format(type_)?,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    reflection.bindings.sort_by_key(|b| (b.set, b.binding));
+    reflection.vertex_inputs.sort_by_key(|i| i.location);
+
+    Ok(reflection)
+}
+
+fn get_stage(execution_model: u32) -> Result<vk::ShaderStageFlags> {
+    match execution_model {
+        0 => Ok(vk::ShaderStageFlags::VERTEX),
+        1 => Ok(vk::ShaderStageFlags::TESSELLATION_CONTROL),
+        2 => Ok(vk::ShaderStageFlags::TESSELLATION_EVALUATION),
+        3 => Ok(vk::ShaderStageFlags::GEOMETRY),
+        4 => Ok(vk::ShaderStageFlags::FRAGMENT),
+        5 => Ok(vk::ShaderStageFlags::COMPUTE),
+        _ => Err(anyhow!("Unsupported SPIR-V execution model {}.", execution_model)),
+    }
+}
+
+struct Module {
+    types: HashMap<u32, Type>,
+    constants: HashMap<u32, u32>,
+    decorations: HashMap<u32, Decorations>,
+}
+
+impl Module {
+    fn get_array_element(&self, type_: u32) -> (u32, u32) {
+        match self.types.get(&type_) {
+            Some(Type::Array { element, length }) => {
+                (*element, self.constants.get(length).copied().unwrap_or(1))
+            }
+            Some(Type::RuntimeArray { element }) => (*element, 1),
+            _ => (type_, 1),
+        }
+    }
+
+    fn get_descriptor_type(&self, type_: u32, storage_class: u32) -> Result<vk::DescriptorType> {
+        let decoration = self.decorations.get(&type_);
+        match (self.types.get(&type_), storage_class) {
+            (Some(Type::Struct { .. }), STORAGE_STORAGE_BUFFER) => Ok(vk::DescriptorType::STORAGE_BUFFER),
+            (Some(Type::Struct { .. }), STORAGE_UNIFORM) if decoration.is_some_and(|d| d.buffer_block) => {
+                Ok(vk::DescriptorType::STORAGE_BUFFER)
+            }
+            (Some(Type::Struct { .. }), STORAGE_UNIFORM) => Ok(vk::DescriptorType::UNIFORM_BUFFER),
+            (Some(Type::SampledImage), _) => Ok(vk::DescriptorType::COMBINED_IMAGE_SAMPLER),
+            (Some(Type::Sampler), _) => Ok(vk::DescriptorType::SAMPLER),
+            (Some(Type::Image { dim: DIM_SUBPASS_DATA, .. }), _) => Ok(vk::DescriptorType::INPUT_ATTACHMENT),
+            (Some(Type::Image { dim: DIM_BUFFER, sampled: 2 }), _) => Ok(vk::DescriptorType::STORAGE_TEXEL_BUFFER),
+            (Some(Type::Image { dim: DIM_BUFFER, .. }), _) => Ok(vk::DescriptorType::UNIFORM_TEXEL_BUFFER),
+            (Some(Type::Image { sampled: 2, .. }), _) => Ok(vk::DescriptorType::STORAGE_IMAGE),
+            (Some(Type::Image { .. }), _) => Ok(vk::DescriptorType::SAMPLED_IMAGE),
+            (type_, _) => Err(anyhow!("Unsupported descriptor type {:?}.", type_)),
+        }
+    }
+
+    /// Gets the size of a type in a block; `matrix_stride` comes from the member decoration.
+    fn get_size(&self, type_: u32, matrix_stride: Option<u32>) -> Result<u32> {
+        match self.types.get(&type_) {
+            Some(Type::Scalar { width, .. }) => Ok(width / 8),
+            Some(Type::Vector { component, count }) => Ok(self.get_size(*component, None)? * count),
+            Some(Type::Matrix { column, count }) => {
+                let stride = match matrix_stride {
+                    Some(stride) => stride,
+                    None => self.get_size(*column, None)?,
+                };
+
+                Ok(stride * count)
+            }
+            Some(Type::Array { element, length }) => {
+                let length = self.constants.get(length).copied().unwrap_or(1);
+                let stride = match self.decorations.get(&type_).and_then(|d| d.array_stride) {
+                    Some(stride) => stride,
+                    None => self.get_size(*element, None)?,
+                };
+
+                Ok(stride * length)
+            }
+            Some(Type::RuntimeArray { .. }) => Ok(0),
+            Some(Type::Struct { members }) => {
+                let decoration = self.decorations.get(&type_).cloned().unwrap_or_default();
+                let mut size = 0;
+                let mut offset = 0;
+                for (index, member) in members.iter().enumerate() {
+                    let index = index as u32;
+                    offset = decoration.member_offsets.get(&index).copied().unwrap_or(offset);
+                    let matrix_stride = decoration.member_matrix_strides.get(&index).copied();
+                    let member_size = self.get_size(*member, matrix_stride)?;
+                    size = size.max(offset + member_size);
+                    offset += member_size;
+                }
+
+                Ok(size)
+            }
+            type_ => Err(anyhow!("Cannot compute the size of {:?}.", type_)),
+        }
+    }
+
+    fn get_format(&self, type_: u32) -> Result<vk::Format> {
+        let (component, count) = match self.types.get(&type_) {
+            Some(Type::Vector { component, count }) => (*component, *count),
+            Some(Type::Scalar { .. }) => (type_, 1),
+            type_ => return Err(anyhow!("Unsupported vertex input type {:?}.", type_)),
+        };
+
+        let format = match (self.types.get(&component), count) {
+            (Some(Type::Scalar { float: true, width: 32, .. }), 1) => vk::Format::R32_SFLOAT,
+            (Some(Type::Scalar { float: true, width: 32, .. }), 2) => vk::Format::R32G32_SFLOAT,
+            (Some(Type::Scalar { float: true, width: 32, .. }), 3) => vk::Format::R32G32B32_SFLOAT,
+            (Some(Type::Scalar { float: true, width: 32, .. }), 4) => vk::Format::R32G32B32A32_SFLOAT,
+            (Some(Type::Scalar { float: false, signed: true, width: 32 }), 1) => vk::Format::R32_SINT,
+            (Some(Type::Scalar { float: false, signed: true, width: 32 }), 2) => vk::Format::R32G32_SINT,
+            (Some(Type::Scalar { float: false, signed: true, width: 32 }), 3) => vk::Format::R32G32B32_SINT,
+            (Some(Type::Scalar { float: false, signed: true, width: 32 }), 4) => vk::Format::R32G32B32A32_SINT,
+            (Some(Type::Scalar { float: false, signed: false, width: 32 }), 1) => vk::Format::R32_UINT,
+            (Some(Type::Scalar { float: false, signed: false, width: 32 }), 2) => vk::Format::R32G32_UINT,
+            (Some(Type::Scalar { float: false, signed: false, width: 32 }), 3) => vk::Format::R32G32B32_UINT,
+            (Some(Type::Scalar { float: false, signed: false, width: 32 }), 4) => vk::Format::R32G32B32A32_UINT,
+            (type_, count) => return Err(anyhow!("Unsupported vertex input type {:?} x{}.", type_, count)),
+        };
+
+        Ok(format)
+    }
+}
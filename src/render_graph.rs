@@ -0,0 +1,850 @@
+//! A render graph that derives render passes, framebuffers, transient
+//! attachments and the barriers between passes from the attachments each pass
+//! declares it writes and samples. Multisampled color attachments can be
+//! resolved into single-sampled ones, such as the backbuffer, at the end of a
+//! pass.
+//!
+//! Where the device supports Vulkan 1.3, passes are recorded with dynamic
+//! rendering and synchronization2 barriers instead of render pass objects.
+
+use anyhow::{anyhow, Result};
+use vulkanalia::prelude::v1_0::*;
+use vulkanalia::vk::DeviceV1_3;
+
+use crate::app_data::AppData;
+use crate::buffer::create_image;
+use crate::capabilities::{DeviceCapabilities, DeviceRequirements, Feature};
+use crate::image::create_image_view;
+use crate::owned::{Owned, SharedDevice};
+use crate::profiler::FRAME_SCOPE;
+use crate::stats::DrawCounts;
+
+
+/// Records the commands of a pass inside its render pass.
+///
+/// Called with the swapchain image index being recorded, and counts the
+/// draws and binds it records.
+pub type RecordFn = unsafe fn(&Device, &AppData, vk::CommandBuffer, usize, &mut DrawCounts) -> Result<()>;
+
+/// An attachment declared in a [`RenderGraph`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AttachmentId(usize);
+
+/// The swapchain image being rendered (or the offscreen target when headless).
+pub const BACKBUFFER: AttachmentId = AttachmentId(0);
+
+/// Dynamic rendering is used where supported, unless disabled in the settings.
+pub fn declare_requirements(data: &AppData, requirements: &mut DeviceRequirements) {
+    if data.settings.dynamic_rendering {
+        requirements.request_feature(Feature::DynamicRendering);
+        requirements.request_feature(Feature::Synchronization2);
+    }
+}
+
+/// How passes are recorded, chosen when the logical device is created.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum RenderBackend {
+    /// Render pass and framebuffer objects, with `vkCmdPipelineBarrier` barriers.
+    #[default]
+    RenderPass,
+    /// Vulkan 1.3 dynamic rendering, with `vkCmdPipelineBarrier2` barriers.
+    Dynamic,
+}
+
+impl RenderBackend {
+    pub fn select(capabilities: &DeviceCapabilities) -> Self {
+        if capabilities.has_feature(Feature::DynamicRendering) && capabilities.has_feature(Feature::Synchronization2) {
+            Self::Dynamic
+        } else {
+            Self::RenderPass
+        }
+    }
+}
+
+/// What the pipelines used in a pass are created for.
+#[derive(Clone, Debug)]
+pub enum PassTarget {
+    RenderPass(Owned<vk::RenderPass>),
+    /// The formats of the attachments, since there is no render pass.
+    Dynamic {
+        color_formats: Vec<vk::Format>,
+        depth_format: vk::Format,
+        stencil_format: vk::Format,
+    },
+}
+
+impl Default for PassTarget {
+    fn default() -> Self {
+        Self::RenderPass(Owned::default())
+    }
+}
+
+/// What happens to the contents of an attachment at the start of a pass.
+#[derive(Copy, Clone, Debug)]
+pub enum LoadOp {
+    Clear(vk::ClearValue),
+    Load,
+    DontCare,
+}
+
+/// How a pass uses an attachment.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Usage {
+    Color,
+    Depth,
+    /// The single-sampled target of a multisample resolve.
+    Resolve,
+    Sampled,
+}
+
+impl Usage {
+    fn layout(self) -> vk::ImageLayout {
+        match self {
+            Usage::Color | Usage::Resolve => vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            Usage::Depth => vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+            Usage::Sampled => vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }
+    }
+
+    fn stages(self) -> vk::PipelineStageFlags {
+        match self {
+            Usage::Color | Usage::Resolve => vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            Usage::Depth => vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+            Usage::Sampled => vk::PipelineStageFlags::FRAGMENT_SHADER,
+        }
+    }
+
+    fn access(self) -> vk::AccessFlags {
+        match self {
+            Usage::Color => vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+            Usage::Resolve => vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+            Usage::Depth => vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            Usage::Sampled => vk::AccessFlags::SHADER_READ,
+        }
+    }
+
+    fn image_usage(self) -> vk::ImageUsageFlags {
+        match self {
+            Usage::Color | Usage::Resolve => vk::ImageUsageFlags::COLOR_ATTACHMENT,
+            Usage::Depth => vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+            Usage::Sampled => vk::ImageUsageFlags::SAMPLED,
+        }
+    }
+}
+
+/// A pass and the attachments it writes and samples.
+#[derive(Clone, Debug)]
+pub struct Pass {
+    name: &'static str,
+    colors: Vec<(AttachmentId, LoadOp)>,
+    depth: Option<(AttachmentId, LoadOp)>,
+    /// The attachment each color attachment is resolved into, if any.
+    resolves: Vec<Option<AttachmentId>>,
+    sampled: Vec<AttachmentId>,
+    record: RecordFn,
+}
+
+impl Pass {
+    pub fn new(name: &'static str, record: RecordFn) -> Self {
+        Self { name, colors: vec![], depth: None, resolves: vec![], sampled: vec![], record }
+    }
+
+    /// Adds a color attachment, in the order of the fragment shader outputs.
+    pub fn color(mut self, attachment: AttachmentId, load: LoadOp) -> Self {
+        self.colors.push((attachment, load));
+        self.resolves.push(None);
+        self
+    }
+
+    /// Resolves the multisampled color attachment added last into `attachment`
+    /// when the pass ends.
+    pub fn resolve(mut self, attachment: AttachmentId) -> Self {
+        if let Some(resolve) = self.resolves.last_mut() {
+            *resolve = Some(attachment);
+        }
+
+        self
+    }
+
+    pub fn depth(mut self, attachment: AttachmentId, load: LoadOp) -> Self {
+        self.depth = Some((attachment, load));
+        self
+    }
+
+    /// Reads an attachment written by an earlier pass from a shader.
+    pub fn sample(mut self, attachment: AttachmentId) -> Self {
+        self.sampled.push(attachment);
+        self
+    }
+
+    fn attachments(&self) -> impl Iterator<Item = (AttachmentId, LoadOp, Usage)> + '_ {
+        let colors = self.colors.iter().map(|(a, l)| (*a, *l, Usage::Color));
+        let depth = self.depth.iter().map(|(a, l)| (*a, *l, Usage::Depth));
+        let resolves = self.resolves.iter().flatten().map(|a| (*a, LoadOp::DontCare, Usage::Resolve));
+        colors.chain(depth).chain(resolves)
+    }
+
+    fn uses(&self) -> impl Iterator<Item = (AttachmentId, Usage)> + '_ {
+        let sampled = self.sampled.iter().map(|a| (*a, Usage::Sampled));
+        self.attachments().map(|(a, _, u)| (a, u)).chain(sampled)
+    }
+}
+
+/// The swapchain images a graph renders into.
+#[derive(Copy, Clone, Debug)]
+pub struct Backbuffer<'a> {
+    pub format: vk::Format,
+    pub extent: vk::Extent2D,
+    pub images: &'a [vk::Image],
+    pub views: &'a [Owned<vk::ImageView>],
+    /// The layout the image is left in once the graph has finished with it.
+    pub final_layout: vk::ImageLayout,
+}
+
+#[derive(Clone, Debug)]
+struct Attachment {
+    name: &'static str,
+    format: vk::Format,
+    samples: vk::SampleCountFlags,
+    image: Owned<vk::Image>,
+    view: Owned<vk::ImageView>,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Barrier {
+    attachment: AttachmentId,
+    old_layout: vk::ImageLayout,
+    new_layout: vk::ImageLayout,
+    src_stages: vk::PipelineStageFlags,
+    dst_stages: vk::PipelineStageFlags,
+    src_access: vk::AccessFlags,
+    dst_access: vk::AccessFlags,
+}
+
+/// The Vulkan objects created for a pass.
+#[derive(Clone, Debug, Default)]
+struct PassObjects {
+    target: PassTarget,
+    /// One per swapchain image, only used with render passes.
+    framebuffers: Vec<Owned<vk::Framebuffer>>,
+    /// Recorded before the pass begins.
+    barriers: Vec<Barrier>,
+    /// The load and store ops of each attachment, as with [`Pass::attachments`].
+    ops: Vec<(vk::AttachmentLoadOp, vk::AttachmentStoreOp)>,
+    clear_values: Vec<vk::ClearValue>,
+}
+
+/// The passes that make up a frame, in submission order.
+#[derive(Clone, Debug, Default)]
+pub struct RenderGraph {
+    backend: RenderBackend,
+    attachments: Vec<Attachment>,
+    passes: Vec<Pass>,
+    objects: Vec<PassObjects>,
+    /// Leaves the backbuffer in its final layout, which render passes do themselves.
+    final_barriers: Vec<Barrier>,
+    extent: vk::Extent2D,
+    images: Vec<vk::Image>,
+    views: Vec<vk::ImageView>,
+}
+
+impl RenderGraph {
+    pub fn new(backend: RenderBackend) -> Self {
+        let backbuffer = Attachment {
+            name: "backbuffer",
+            format: vk::Format::UNDEFINED,
+            samples: vk::SampleCountFlags::_1,
+            image: Owned::default(),
+            view: Owned::default(),
+        };
+
+        Self { backend, attachments: vec![backbuffer], ..Default::default() }
+    }
+
+    /// Declares a transient attachment the size of the swapchain, created by the graph.
+    pub fn add_attachment(
+        &mut self,
+        name: &'static str,
+        format: vk::Format,
+        samples: vk::SampleCountFlags,
+    ) -> AttachmentId {
+        self.attachments.push(Attachment { name, format, samples, image: Owned::default(), view: Owned::default() });
+        AttachmentId(self.attachments.len() - 1)
+    }
+
+    pub fn add_pass(&mut self, pass: Pass) {
+        self.passes.push(pass);
+    }
+
+    /// The names of the passes, in submission order.
+    pub fn pass_names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.passes.iter().map(|p| p.name)
+    }
+
+    /// Gets what a pass renders into, for creating pipelines.
+    pub fn target(&self, name: &str) -> Result<&PassTarget> {
+        self.passes
+            .iter()
+            .position(|p| p.name == name)
+            .and_then(|i| self.objects.get(i))
+            .map(|o| &o.target)
+            .ok_or_else(|| anyhow!("No pass has been built for `{}`.", name))
+    }
+
+    /// Gets the view of a transient attachment, for sampling it in a later pass.
+    pub fn view(&self, attachment: AttachmentId) -> &Owned<vk::ImageView> {
+        &self.attachments[attachment.0].view
+    }
+
+    /// Creates (or recreates, when the swapchain changes) everything the passes need.
+    pub unsafe fn build(&mut self, device: &SharedDevice, backbuffer: &Backbuffer) -> Result<()> {
+        self.attachments[BACKBUFFER.0].format = backbuffer.format;
+        self.validate()?;
+
+        self.extent = backbuffer.extent;
+        self.images = backbuffer.images.to_vec();
+        self.views = backbuffer.views.iter().map(|v| **v).collect();
+
+        for index in 1..self.attachments.len() {
+            self.create_attachment(device, AttachmentId(index))?;
+        }
+
+        let barriers = self.barriers();
+
+        let mut objects = vec![];
+        for (index, pass) in self.passes.iter().enumerate() {
+            let ops = self.attachment_ops(index);
+
+            let clear_values = pass.attachments()
+                .map(|(_, l, _)| match l {
+                    LoadOp::Clear(value) => value,
+                    _ => vk::ClearValue::default(),
+                })
+                .collect();
+
+            if self.backend == RenderBackend::Dynamic {
+                let depth_format = pass.depth.map_or(vk::Format::UNDEFINED, |(a, _)| self.attachments[a.0].format);
+                let target = PassTarget::Dynamic {
+                    color_formats: pass.colors.iter().map(|(a, _)| self.attachments[a.0].format).collect(),
+                    depth_format,
+                    stencil_format: if has_stencil(depth_format) { depth_format } else { vk::Format::UNDEFINED },
+                };
+
+                objects.push(PassObjects { target, framebuffers: vec![], barriers: barriers[index].clone(), ops, clear_values });
+                continue;
+            }
+
+            let render_pass = self.create_render_pass(device, index, &ops, backbuffer)?;
+
+            let framebuffers = backbuffer.views
+                .iter()
+                .map(|backbuffer_view| {
+                    let views = pass.attachments()
+                        .map(|(a, _, _)| if a == BACKBUFFER { backbuffer_view } else { &self.attachments[a.0].view })
+                        .collect::<Vec<_>>();
+
+                    let attachments = views.iter().map(|v| ***v).collect::<Vec<_>>();
+                    let info = vk::FramebufferCreateInfo::builder()
+                        .render_pass(*render_pass)
+                        .attachments(&attachments)
+                        .width(self.extent.width)
+                        .height(self.extent.height)
+                        .layers(1);
+
+                    let framebuffer = Owned::new(device, device.create_framebuffer(&info, None)?);
+                    Ok(views.into_iter().fold(framebuffer.depends_on(&render_pass), |f, v| f.depends_on(v)))
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            let target = PassTarget::RenderPass(render_pass);
+            objects.push(PassObjects { target, framebuffers, barriers: barriers[index].clone(), ops, clear_values });
+        }
+
+        self.objects = objects;
+        self.final_barriers = match self.backend {
+            RenderBackend::RenderPass => vec![],
+            RenderBackend::Dynamic => self.final_barriers(backbuffer.final_layout),
+        };
+
+        Ok(())
+    }
+
+    /// Records every pass of a frame in flight for a swapchain image.
+    ///
+    /// Each pass is a profiler scope, following [`FRAME_SCOPE`].
+    pub unsafe fn record(
+        &self,
+        device: &Device,
+        data: &AppData,
+        command_buffer: vk::CommandBuffer,
+        frame: usize,
+        image_index: usize,
+        counts: &mut DrawCounts,
+    ) -> Result<()> {
+        for (index, (pass, objects)) in self.passes.iter().zip(&self.objects).enumerate() {
+            let scope = FRAME_SCOPE + 1 + index;
+            data.profiler.begin_scope(device, command_buffer, frame, scope);
+
+            if !objects.barriers.is_empty() {
+                self.record_barriers(device, command_buffer, image_index, &objects.barriers);
+            }
+
+            let render_area = vk::Rect2D::builder()
+                .offset(vk::Offset2D::default())
+                .extent(self.extent)
+                .build();
+
+            match &objects.target {
+                PassTarget::RenderPass(render_pass) => {
+                    let info = vk::RenderPassBeginInfo::builder()
+                        .render_pass(**render_pass)
+                        .framebuffer(*objects.framebuffers[image_index])
+                        .render_area(render_area)
+                        .clear_values(&objects.clear_values);
+
+                    device.cmd_begin_render_pass(command_buffer, &info, vk::SubpassContents::INLINE);
+                    (pass.record)(device, data, command_buffer, image_index, counts)?;
+                    device.cmd_end_render_pass(command_buffer);
+                }
+                PassTarget::Dynamic { .. } => {
+                    self.begin_rendering(device, command_buffer, image_index, pass, objects, render_area);
+                    (pass.record)(device, data, command_buffer, image_index, counts)?;
+                    device.cmd_end_rendering(command_buffer);
+                }
+            }
+
+            data.profiler.end_scope(device, command_buffer, frame, scope);
+        }
+
+        if !self.final_barriers.is_empty() {
+            self.record_barriers(device, command_buffer, image_index, &self.final_barriers);
+        }
+
+        Ok(())
+    }
+
+    /// Begins dynamic rendering into the attachments of a pass.
+    unsafe fn begin_rendering(
+        &self,
+        device: &Device,
+        command_buffer: vk::CommandBuffer,
+        image_index: usize,
+        pass: &Pass,
+        objects: &PassObjects,
+        render_area: vk::Rect2D,
+    ) {
+        let view = |a: AttachmentId| if a == BACKBUFFER { self.views[image_index] } else { *self.attachments[a.0].view };
+
+        let attachment_info = |index: usize, attachment: AttachmentId, usage: Usage| {
+            let (load_op, store_op) = objects.ops[index];
+            vk::RenderingAttachmentInfo::builder()
+                .image_view(view(attachment))
+                .image_layout(usage.layout())
+                .load_op(load_op)
+                .store_op(store_op)
+                .clear_value(objects.clear_values[index])
+        };
+
+        // Resolves are always written, so they have no load or store ops of their own.
+        let color_attachments = pass.colors
+            .iter()
+            .zip(&pass.resolves)
+            .enumerate()
+            .map(|(index, ((attachment, _), resolve))| match resolve {
+                Some(resolve) => attachment_info(index, *attachment, Usage::Color)
+                    .resolve_mode(vk::ResolveModeFlags::AVERAGE)
+                    .resolve_image_view(view(*resolve))
+                    .resolve_image_layout(Usage::Resolve.layout()),
+                None => attachment_info(index, *attachment, Usage::Color),
+            })
+            .collect::<Vec<_>>();
+
+        let depth = pass.depth.map(|(a, _)| a);
+        let depth_attachment = depth.map(|a| attachment_info(pass.colors.len(), a, Usage::Depth));
+
+        let mut info = vk::RenderingInfo::builder()
+            .render_area(render_area)
+            .layer_count(1)
+            .color_attachments(&color_attachments);
+
+        if let (Some(depth), Some(depth_attachment)) = (depth, &depth_attachment) {
+            info = info.depth_attachment(depth_attachment);
+            if has_stencil(self.attachments[depth.0].format) {
+                info = info.stencil_attachment(depth_attachment);
+            }
+        }
+
+        device.cmd_begin_rendering(command_buffer, &info);
+    }
+
+    fn validate(&self) -> Result<()> {
+        let mut written = vec![false; self.attachments.len()];
+        for pass in &self.passes {
+            if pass.attachments().next().is_none() {
+                return Err(anyhow!("Pass `{}` has no attachments.", pass.name));
+            }
+
+            for (attachment, usage) in pass.uses() {
+                if attachment.0 >= self.attachments.len() {
+                    return Err(anyhow!("Pass `{}` uses an undeclared attachment.", pass.name));
+                }
+
+                let name = self.attachments[attachment.0].name;
+                if usage == Usage::Sampled && attachment == BACKBUFFER {
+                    return Err(anyhow!("Pass `{}` samples the backbuffer.", pass.name));
+                } else if usage == Usage::Sampled && pass.attachments().any(|(a, _, _)| a == attachment) {
+                    return Err(anyhow!("Pass `{}` both writes and samples `{}`.", pass.name, name));
+                } else if usage == Usage::Sampled && !written[attachment.0] {
+                    return Err(anyhow!("Pass `{}` samples `{}` before any pass writes it.", pass.name, name));
+                }
+            }
+
+            self.validate_samples(pass)?;
+
+            pass.attachments().for_each(|(a, _, _)| written[a.0] = true);
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the color and depth attachments of a pass share a sample
+    /// count and that resolves go from a multisampled attachment to a
+    /// single-sampled one of the same format.
+    fn validate_samples(&self, pass: &Pass) -> Result<()> {
+        let targets = pass.colors.iter().map(|(a, _)| a).chain(pass.depth.iter().map(|(a, _)| a));
+        let samples = targets.map(|a| self.attachments[a.0].samples).collect::<Vec<_>>();
+        if samples.windows(2).any(|s| s[0] != s[1]) {
+            return Err(anyhow!("Pass `{}` mixes attachments with different sample counts.", pass.name));
+        }
+
+        for ((color, _), resolve) in pass.colors.iter().zip(&pass.resolves) {
+            let (color, resolve) = match resolve {
+                Some(resolve) => (&self.attachments[color.0], &self.attachments[resolve.0]),
+                None => continue,
+            };
+
+            if color.samples == vk::SampleCountFlags::_1 {
+                return Err(anyhow!("Pass `{}` resolves single-sampled `{}`.", pass.name, color.name));
+            } else if resolve.samples != vk::SampleCountFlags::_1 {
+                return Err(anyhow!("Pass `{}` resolves into multisampled `{}`.", pass.name, resolve.name));
+            } else if color.format != resolve.format {
+                return Err(anyhow!("Pass `{}` resolves `{}` into `{}` of a different format.", pass.name, color.name, resolve.name));
+            }
+        }
+
+        Ok(())
+    }
+
+    unsafe fn create_attachment(&mut self, device: &SharedDevice, attachment: AttachmentId) -> Result<()> {
+        let usage = self.passes
+            .iter()
+            .flat_map(|p| p.uses())
+            .filter(|(a, _)| *a == attachment)
+            .fold(vk::ImageUsageFlags::empty(), |f, (_, u)| f | u.image_usage());
+
+        // Attachments that are never sampled only live inside render passes,
+        // so tiled GPUs can keep them in tile memory.
+        let usage = if usage.contains(vk::ImageUsageFlags::SAMPLED) {
+            usage
+        } else {
+            usage | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT
+        };
+
+        let Attachment { format, samples, .. } = self.attachments[attachment.0];
+
+        let image = create_image(
+            device,
+            self.extent.width,
+            self.extent.height,
+            1,
+            samples,
+            format,
+            vk::ImageTiling::OPTIMAL,
+            usage,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        )?;
+
+        let aspects = if is_depth_format(format) { vk::ImageAspectFlags::DEPTH } else { vk::ImageAspectFlags::COLOR };
+        let view = create_image_view(device, *image, format, 1, aspects)?.depends_on(&image);
+
+        let attachment = &mut self.attachments[attachment.0];
+        attachment.image = image;
+        attachment.view = view;
+
+        Ok(())
+    }
+
+    /// Computes the barriers recorded before each pass.
+    ///
+    /// Every use of an attachment is preceded by a barrier from its previous
+    /// use, so render passes never transition layouts except for leaving the
+    /// backbuffer in its final layout. The first use in a frame waits on the
+    /// last use in the previous frame, since transient attachments are shared
+    /// by every frame in flight.
+    fn barriers(&self) -> Vec<Vec<Barrier>> {
+        let mut previous = vec![None; self.attachments.len()];
+        for (attachment, usage) in self.passes.iter().flat_map(|p| p.uses()) {
+            previous[attachment.0] = Some(usage);
+        }
+
+        let mut first = vec![true; self.attachments.len()];
+        let mut barriers = vec![];
+        for pass in &self.passes {
+            let mut pass_barriers = vec![];
+            for (attachment, usage) in pass.uses() {
+                let last = previous[attachment.0].replace(usage).unwrap_or(usage);
+                let first = std::mem::replace(&mut first[attachment.0], false);
+
+                // Reads of an attachment that is already readable need no barrier.
+                if !first && last == Usage::Sampled && usage == Usage::Sampled {
+                    continue;
+                }
+
+                let (old_layout, src_stages, src_access) = if first && attachment == BACKBUFFER {
+                    // The acquire semaphore is waited on at this stage.
+                    (vk::ImageLayout::UNDEFINED, vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT, vk::AccessFlags::empty())
+                } else if first {
+                    (vk::ImageLayout::UNDEFINED, last.stages(), last.access())
+                } else {
+                    (last.layout(), last.stages(), last.access())
+                };
+
+                pass_barriers.push(Barrier {
+                    attachment,
+                    old_layout,
+                    new_layout: usage.layout(),
+                    src_stages,
+                    dst_stages: usage.stages(),
+                    src_access,
+                    dst_access: usage.access(),
+                });
+            }
+
+            barriers.push(pass_barriers);
+        }
+
+        barriers
+    }
+
+    /// Gets the load and store ops of each attachment of a pass.
+    ///
+    /// Attachments are only stored if a later pass uses them or if they are the backbuffer.
+    fn attachment_ops(&self, index: usize) -> Vec<(vk::AttachmentLoadOp, vk::AttachmentStoreOp)> {
+        let later = &self.passes[index + 1..];
+
+        self.passes[index]
+            .attachments()
+            .map(|(attachment, load, _)| {
+                let used_later = later.iter().any(|p| p.uses().any(|(a, _)| a == attachment));
+
+                let load_op = match load {
+                    LoadOp::Clear(_) => vk::AttachmentLoadOp::CLEAR,
+                    LoadOp::Load => vk::AttachmentLoadOp::LOAD,
+                    LoadOp::DontCare => vk::AttachmentLoadOp::DONT_CARE,
+                };
+
+                let store_op = if attachment == BACKBUFFER || used_later {
+                    vk::AttachmentStoreOp::STORE
+                } else {
+                    vk::AttachmentStoreOp::DONT_CARE
+                };
+
+                (load_op, store_op)
+            })
+            .collect()
+    }
+
+    /// Computes the barriers that leave the backbuffer in its final layout after the last pass.
+    fn final_barriers(&self, final_layout: vk::ImageLayout) -> Vec<Barrier> {
+        let last = self.passes.iter().flat_map(|p| p.uses()).filter(|(a, _)| *a == BACKBUFFER).last();
+
+        // Presenting waits on a semaphore, and headless frames are copied
+        // after the submission completes, so nothing later needs to wait here.
+        last.map(|(_, usage)| Barrier {
+            attachment: BACKBUFFER,
+            old_layout: usage.layout(),
+            new_layout: final_layout,
+            src_stages: usage.stages(),
+            dst_stages: vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+            src_access: usage.access(),
+            dst_access: vk::AccessFlags::empty(),
+        })
+        .into_iter()
+        .collect()
+    }
+
+    unsafe fn create_render_pass(
+        &self,
+        device: &SharedDevice,
+        index: usize,
+        ops: &[(vk::AttachmentLoadOp, vk::AttachmentStoreOp)],
+        backbuffer: &Backbuffer,
+    ) -> Result<Owned<vk::RenderPass>> {
+        let pass = &self.passes[index];
+        let later = &self.passes[index + 1..];
+
+        let attachments = pass.attachments()
+            .zip(ops)
+            .map(|((attachment, _, usage), (load_op, store_op))| {
+                let used_later = later.iter().any(|p| p.uses().any(|(a, _)| a == attachment));
+                let final_layout = if attachment == BACKBUFFER && !used_later {
+                    backbuffer.final_layout
+                } else {
+                    usage.layout()
+                };
+
+                vk::AttachmentDescription::builder()
+                    .format(self.attachments[attachment.0].format)
+                    .samples(self.attachments[attachment.0].samples)
+                    .load_op(*load_op)
+                    .store_op(*store_op)
+                    .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+                    .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+                    .initial_layout(usage.layout())
+                    .final_layout(final_layout)
+                    .build()
+            })
+            .collect::<Vec<_>>();
+
+        let color_attachments = (0..pass.colors.len())
+            .map(|i| vk::AttachmentReference { attachment: i as u32, layout: Usage::Color.layout() })
+            .collect::<Vec<_>>();
+
+        let depth_stencil_attachment = vk::AttachmentReference {
+            attachment: pass.colors.len() as u32,
+            layout: Usage::Depth.layout(),
+        };
+
+        // Resolve attachments follow the color and depth attachments.
+        let mut next_resolve = pass.colors.len() + pass.depth.iter().count();
+        let resolve_attachments = pass.resolves
+            .iter()
+            .map(|r| match r {
+                Some(_) => {
+                    next_resolve += 1;
+                    vk::AttachmentReference { attachment: next_resolve as u32 - 1, layout: Usage::Resolve.layout() }
+                }
+                None => vk::AttachmentReference { attachment: vk::ATTACHMENT_UNUSED, layout: vk::ImageLayout::UNDEFINED },
+            })
+            .collect::<Vec<_>>();
+
+        let mut subpass = vk::SubpassDescription::builder()
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .color_attachments(&color_attachments);
+
+        if pass.depth.is_some() {
+            subpass = subpass.depth_stencil_attachment(&depth_stencil_attachment);
+        }
+
+        if pass.resolves.iter().any(Option::is_some) {
+            subpass = subpass.resolve_attachments(&resolve_attachments);
+        }
+
+        let subpasses = &[subpass];
+        let info = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments)
+            .subpasses(subpasses);
+
+        Ok(Owned::new(device, device.create_render_pass(&info, None)?))
+    }
+
+    unsafe fn record_barriers(
+        &self,
+        device: &Device,
+        command_buffer: vk::CommandBuffer,
+        image_index: usize,
+        barriers: &[Barrier],
+    ) {
+        let image_barriers = barriers
+            .iter()
+            .map(|b| {
+                let Attachment { format, image, .. } = &self.attachments[b.attachment.0];
+                let image = if b.attachment == BACKBUFFER { self.images[image_index] } else { **image };
+
+                let subresource = vk::ImageSubresourceRange::builder()
+                    .aspect_mask(get_aspect_mask(*format))
+                    .base_mip_level(0)
+                    .level_count(1)
+                    .base_array_layer(0)
+                    .layer_count(1);
+
+                vk::ImageMemoryBarrier::builder()
+                    .old_layout(b.old_layout)
+                    .new_layout(b.new_layout)
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .image(image)
+                    .subresource_range(subresource)
+                    .src_access_mask(b.src_access)
+                    .dst_access_mask(b.dst_access)
+                    .build()
+            })
+            .collect::<Vec<_>>();
+
+        if self.backend == RenderBackend::Dynamic {
+            let image_barriers = image_barriers
+                .iter()
+                .zip(barriers)
+                .map(|(i, b)| {
+                    // Stage and access bits are the same in both versions of the flags.
+                    vk::ImageMemoryBarrier2::builder()
+                        .src_stage_mask(vk::PipelineStageFlags2::from_bits_truncate(b.src_stages.bits().into()))
+                        .dst_stage_mask(vk::PipelineStageFlags2::from_bits_truncate(b.dst_stages.bits().into()))
+                        .src_access_mask(vk::AccessFlags2::from_bits_truncate(b.src_access.bits().into()))
+                        .dst_access_mask(vk::AccessFlags2::from_bits_truncate(b.dst_access.bits().into()))
+                        .old_layout(i.old_layout)
+                        .new_layout(i.new_layout)
+                        .src_queue_family_index(i.src_queue_family_index)
+                        .dst_queue_family_index(i.dst_queue_family_index)
+                        .image(i.image)
+                        .subresource_range(i.subresource_range)
+                })
+                .collect::<Vec<_>>();
+
+            let info = vk::DependencyInfo::builder().image_memory_barriers(&image_barriers);
+            device.cmd_pipeline_barrier2(command_buffer, &info);
+            return;
+        }
+
+        let src_stages = barriers.iter().fold(vk::PipelineStageFlags::empty(), |f, b| f | b.src_stages);
+        let dst_stages = barriers.iter().fold(vk::PipelineStageFlags::empty(), |f, b| f | b.dst_stages);
+
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            src_stages,
+            dst_stages,
+            vk::DependencyFlags::empty(),
+            &[] as &[vk::MemoryBarrier],
+            &[] as &[vk::BufferMemoryBarrier],
+            &image_barriers,
+        );
+    }
+}
+
+fn is_depth_format(format: vk::Format) -> bool {
+    matches!(
+        format,
+        vk::Format::D16_UNORM
+            | vk::Format::D32_SFLOAT
+            | vk::Format::D16_UNORM_S8_UINT
+            | vk::Format::D24_UNORM_S8_UINT
+            | vk::Format::D32_SFLOAT_S8_UINT
+    )
+}
+
+fn has_stencil(format: vk::Format) -> bool {
+    matches!(
+        format,
+        vk::Format::D16_UNORM_S8_UINT | vk::Format::D24_UNORM_S8_UINT | vk::Format::D32_SFLOAT_S8_UINT
+    )
+}
+
+/// Gets every aspect of a format, as required for layout transitions.
+fn get_aspect_mask(format: vk::Format) -> vk::ImageAspectFlags {
+    match format {
+        vk::Format::D16_UNORM_S8_UINT | vk::Format::D24_UNORM_S8_UINT | vk::Format::D32_SFLOAT_S8_UINT =>
+            vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL,
+        vk::Format::D16_UNORM | vk::Format::D32_SFLOAT => vk::ImageAspectFlags::DEPTH,
+        _ => vk::ImageAspectFlags::COLOR,
+    }
+}
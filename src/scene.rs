@@ -0,0 +1,308 @@
+//! The meshes, textures and object instances drawn each frame.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::mem::size_of_val;
+use std::slice;
+
+use anyhow::{anyhow, Result};
+use cgmath::{Deg, SquareMatrix};
+use serde::Deserialize;
+use vulkanalia::vk::{self, Handle};
+use vulkanalia::Instance;
+
+use crate::app_data::AppData;
+use crate::buffer::create_buffer;
+use crate::image::{create_image_view, create_texture_image, create_texture_sampler};
+use crate::mesh::{Mat4, Vec3, Vec4, Vertex};
+use crate::model::{load_gltf, load_image, load_obj, ImageData, Material, Model};
+use crate::owned::{Owned, SharedDevice};
+use crate::upload::UploadTicket;
+
+
+/// A range of a mesh's indices drawn with a single texture and color.
+#[derive(Copy, Clone, Debug)]
+pub struct Submesh {
+    pub first_index: u32,
+    pub index_count: u32,
+    /// An index into [`Scene::textures`].
+    pub texture: usize,
+    /// Multiplied with the texture.
+    pub base_color: Vec4,
+    /// Drawn with alpha blending after every opaque submesh.
+    pub transparent: bool,
+}
+
+/// Vertex and index buffers in device local memory.
+#[derive(Clone, Debug, Default)]
+pub struct Mesh {
+    pub vertex_buffer: Owned<vk::Buffer>,
+    pub index_buffer: Owned<vk::Buffer>,
+    pub submeshes: Vec<Submesh>,
+}
+
+/// A sampled image along with the material descriptor set that binds it.
+#[derive(Clone, Debug, Default)]
+pub struct Texture {
+    pub image: Owned<vk::Image>,
+    pub view: Owned<vk::ImageView>,
+    pub descriptor_set: vk::DescriptorSet,
+}
+
+/// A mesh drawn at a position in the world.
+#[derive(Copy, Clone, Debug)]
+pub struct MeshInstance {
+    /// An index into [`Scene::meshes`].
+    pub mesh: usize,
+    pub transform: Mat4,
+}
+
+/// Where a copy of the model is placed in the scene.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Placement {
+    pub translation: [f32; 3],
+    /// Degrees around the Z axis.
+    pub rotation: f32,
+    pub scale: f32,
+}
+
+impl Default for Placement {
+    fn default() -> Self {
+        Self { translation: [0.0; 3], rotation: 0.0, scale: 1.0 }
+    }
+}
+
+impl Placement {
+    pub fn transform(&self) -> Mat4 {
+        Mat4::from_translation(Vec3::from(self.translation))
+            * Mat4::from_angle_z(Deg(self.rotation))
+            * Mat4::from_scale(self.scale)
+    }
+}
+
+/// The per-draw data pushed before each submesh is drawn.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct PushConstants {
+    pub model: Mat4,
+    pub base_color: Vec4,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Scene {
+    pub meshes: Vec<Mesh>,
+    pub textures: Vec<Texture>,
+    pub instances: Vec<MeshInstance>,
+    /// Shared by every texture.
+    pub sampler: Owned<vk::Sampler>,
+    /// The pool the texture descriptor sets are allocated from.
+    pub descriptor_pool: Owned<vk::DescriptorPool>,
+    /// Completes once every mesh and texture has been uploaded.
+    pub upload: UploadTicket,
+}
+
+impl Scene {
+    /// Adds a mesh, returning its index.
+    pub fn add_mesh(&mut self, mesh: Mesh) -> usize {
+        self.meshes.push(mesh);
+        self.meshes.len() - 1
+    }
+
+    /// Adds a texture, returning its index.
+    pub fn add_texture(&mut self, texture: Texture) -> usize {
+        self.textures.push(texture);
+        self.textures.len() - 1
+    }
+
+    pub fn add_instance(&mut self, mesh: usize, transform: Mat4) {
+        self.instances.push(MeshInstance { mesh, transform });
+    }
+}
+
+
+/// Loads the glTF (`.gltf` or `.glb`) or OBJ file named in the settings into
+/// the scene, once for each placement in the settings or once at the origin.
+///
+/// Material descriptor sets are written later by `create_material_descriptor_sets`.
+/// Every mesh and texture is uploaded in a single batch, which frames
+/// submitted afterwards don't need to wait for.
+pub unsafe fn load_scene(instance: &Instance, device: &SharedDevice, data: &mut AppData) -> Result<()> {
+    let path = &data.settings.model;
+    let model = match path.extension().and_then(OsStr::to_str) {
+        Some("gltf" | "glb") => load_gltf(path)?,
+        _ => load_obj(path)?,
+    };
+
+    let fallback = match &data.settings.texture {
+        Some(path) => load_image(path)?,
+        None => ImageData::solid([1.0; 4]),
+    };
+
+    let placements = match data.settings.instances.as_slice() {
+        [] => vec![Mat4::identity()],
+        instances => instances.iter().map(Placement::transform).collect(),
+    };
+
+    let mut scene = Scene { sampler: create_texture_sampler(device)?, ..Default::default() };
+    add_model(instance, device, data, &mut scene, &model, &fallback, &placements)?;
+    scene.upload = data.uploads.flush(device)?;
+    data.scene = scene;
+
+    Ok(())
+}
+
+/// Uploads the meshes and base color images of a model and adds an instance
+/// for each of its nodes at each placement. Empty meshes are skipped.
+///
+/// Each image is uploaded once no matter how many materials use it. Materials
+/// without a base color texture are drawn with `fallback`.
+pub unsafe fn add_model(
+    instance: &Instance,
+    device: &SharedDevice,
+    data: &mut AppData,
+    scene: &mut Scene,
+    model: &Model,
+    fallback: &ImageData,
+    placements: &[Mat4],
+) -> Result<()> {
+    let default_material = Material::default();
+
+    // The scene texture of each model image, with `None` for the fallback texture.
+    let mut textures = HashMap::<Option<usize>, usize>::new();
+
+    // The scene mesh of each model mesh, with `None` for empty meshes, which
+    // can't be given zero-sized buffers.
+    let mut meshes = Vec::with_capacity(model.meshes.len());
+    for mesh in &model.meshes {
+        if mesh.vertices.is_empty() || mesh.indices.is_empty() {
+            meshes.push(None);
+            continue;
+        }
+
+        let mut submeshes = Vec::with_capacity(mesh.submeshes.len());
+        for submesh in &mesh.submeshes {
+            let material = match submesh.material {
+                Some(material) => model.materials
+                    .get(material)
+                    .ok_or_else(|| anyhow!("Submesh uses missing material {}.", material))?,
+                None => &default_material,
+            };
+
+            let image = material.base_color_texture;
+            let texture = match textures.get(&image) {
+                Some(texture) => *texture,
+                None => {
+                    let image_data = match image {
+                        Some(image) => model.images
+                            .get(image)
+                            .ok_or_else(|| anyhow!("Material `{}` uses missing image {}.", material.name, image))?,
+                        None => fallback,
+                    };
+
+                    let texture = scene.add_texture(create_texture(instance, device, data, image_data)?);
+                    textures.insert(image, texture);
+                    texture
+                }
+            };
+
+            submeshes.push(Submesh {
+                first_index: submesh.first_index,
+                index_count: submesh.index_count,
+                texture,
+                base_color: material.base_color.into(),
+                transparent: material.transparent,
+            });
+        }
+
+        meshes.push(Some(scene.add_mesh(create_mesh(device, data, &mesh.vertices, &mesh.indices, submeshes)?)));
+    }
+
+    for placement in placements {
+        for node in &model.nodes {
+            if let Some(mesh) = meshes[node.mesh] {
+                scene.add_instance(mesh, placement * node.transform);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Uploads vertices and indices to device local buffers.
+pub unsafe fn create_mesh(
+    device: &SharedDevice,
+    data: &mut AppData,
+    vertices: &[Vertex],
+    indices: &[u32],
+    submeshes: Vec<Submesh>,
+) -> Result<Mesh> {
+    let vertex_buffer = create_device_local_buffer(
+        device,
+        data,
+        vertices,
+        vk::BufferUsageFlags::VERTEX_BUFFER,
+        vk::AccessFlags::VERTEX_ATTRIBUTE_READ,
+    )?;
+
+    let index_buffer = create_device_local_buffer(
+        device,
+        data,
+        indices,
+        vk::BufferUsageFlags::INDEX_BUFFER,
+        vk::AccessFlags::INDEX_READ,
+    )?;
+
+    Ok(Mesh { vertex_buffer, index_buffer, submeshes })
+}
+
+/// Uploads decoded pixels into a texture.
+pub unsafe fn create_texture(
+    instance: &Instance,
+    device: &SharedDevice,
+    data: &mut AppData,
+    image_data: &ImageData,
+) -> Result<Texture> {
+    let (image, mip_levels) = create_texture_image(
+        instance,
+        device,
+        data,
+        image_data.width,
+        image_data.height,
+        &image_data.pixels,
+    )?;
+
+    let view = create_image_view(
+        device,
+        *image,
+        vk::Format::R8G8B8A8_SRGB,
+        mip_levels,
+        vk::ImageAspectFlags::COLOR,
+    )?
+    .depends_on(&image);
+
+    Ok(Texture { image, view, descriptor_set: vk::DescriptorSet::null() })
+}
+
+#[track_caller]
+unsafe fn create_device_local_buffer<T>(
+    device: &SharedDevice,
+    data: &mut AppData,
+    items: &[T],
+    usage: vk::BufferUsageFlags,
+    access: vk::AccessFlags,
+) -> Result<Owned<vk::Buffer>> {
+    let size = size_of_val(items);
+
+    let buffer = create_buffer(
+        device,
+        size as u64,
+        vk::BufferUsageFlags::TRANSFER_DST | usage,
+        vk::MemoryPropertyFlags::DEVICE_LOCAL,
+    )?;
+
+    let bytes = slice::from_raw_parts(items.as_ptr().cast::<u8>(), size);
+    data.uploads.upload_buffer(device, bytes, *buffer, vk::PipelineStageFlags::VERTEX_INPUT, access)?;
+
+    Ok(buffer)
+}
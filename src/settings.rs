@@ -0,0 +1,269 @@
+//! Renderer settings, read from the command line and an optional TOML file.
+
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use serde::Deserialize;
+
+use crate::camera::{CameraMode, Projection};
+use crate::debug::VALIDATION_ENABLED;
+use crate::scene::Placement;
+use crate::syncronization::DEFAULT_FRAMES_IN_FLIGHT;
+
+
+/// The command-line arguments.
+///
+/// Settings given here take precedence over those in the config file.
+#[derive(Clone, Debug, Parser)]
+#[command(about = "Renders a model with Vulkan.", long_about = None)]
+pub struct Args {
+    /// A TOML file to read settings from.
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// A glTF (`.gltf` or `.glb`) or OBJ file to render.
+    #[arg(long)]
+    pub model: Option<PathBuf>,
+    /// An image to use for materials without a base color texture.
+    #[arg(long)]
+    pub texture: Option<PathBuf>,
+    /// Draw a copy of the model at this position. Can be repeated.
+    #[arg(long = "instance", value_name = "X,Y,Z", value_parser = parse_position)]
+    pub instances: Vec<[f32; 3]>,
+    /// The width of the window or headless image.
+    #[arg(long)]
+    pub width: Option<u32>,
+    /// The height of the window or headless image.
+    #[arg(long)]
+    pub height: Option<u32>,
+    /// The window title.
+    #[arg(long)]
+    pub title: Option<String>,
+    /// The color frames are cleared to, as `R,G,B,A`.
+    #[arg(long, value_name = "R,G,B,A", value_parser = parse_color)]
+    pub clear_color: Option<[f32; 4]>,
+    /// How the camera is controlled.
+    #[arg(long)]
+    pub camera: Option<CameraMode>,
+    #[arg(long)]
+    pub projection: Option<Projection>,
+    /// The vertical field of view in degrees.
+    #[arg(long, value_name = "DEGREES")]
+    pub fov: Option<f32>,
+    /// The distance to the near clipping plane.
+    #[arg(long)]
+    pub near: Option<f32>,
+    /// The distance to the far clipping plane.
+    #[arg(long)]
+    pub far: Option<f32>,
+    /// Whether to wait for vertical blanks instead of tearing.
+    #[arg(long)]
+    pub vsync: Option<bool>,
+    /// How many frames the CPU can record ahead of the GPU, from 1 to 8.
+    #[arg(long)]
+    pub frames_in_flight: Option<usize>,
+    /// The number of samples per pixel, clamped to what the device supports.
+    #[arg(long)]
+    pub msaa_samples: Option<u32>,
+    /// Shade at least this fraction of the samples of each pixel separately, from 0 to 1.
+    #[arg(long, value_name = "FRACTION")]
+    pub sample_shading: Option<f32>,
+    /// Whether to render with dynamic rendering where the device supports Vulkan 1.3.
+    #[arg(long)]
+    pub dynamic_rendering: Option<bool>,
+    /// Whether to show draw and pipeline statistics in the window title.
+    #[arg(long)]
+    pub stats_in_title: Option<bool>,
+    /// Whether to enable the validation layer.
+    #[arg(long)]
+    pub validation: Option<bool>,
+    /// The physical device to use, by index, UUID or part of its name, instead of the highest ranked one.
+    #[arg(long, env = "VULKAN_DEVICE", value_name = "INDEX|UUID|NAME")]
+    pub device: Option<String>,
+    /// The file compiled pipelines are cached in between runs.
+    #[arg(long)]
+    pub pipeline_cache: Option<PathBuf>,
+    /// Recompile shaders under `shaders/` and rebuild the pipeline when they change.
+    #[arg(long)]
+    pub hot_reload: bool,
+    /// Measure how long each pass takes on the GPU and write the averages to a JSON file.
+    #[arg(long, value_name = "OUTPUT.json", num_args = 0..=1, default_missing_value = "profile.json")]
+    pub profile: Option<PathBuf>,
+    /// Render a single frame without a window and write it to a PNG.
+    #[arg(long, value_name = "OUTPUT.png", num_args = 0..=1, default_missing_value = "output.png")]
+    pub headless: Option<PathBuf>,
+}
+
+/// The settings the renderer is created with.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub model: PathBuf,
+    /// Used for materials without a base color texture instead of white.
+    pub texture: Option<PathBuf>,
+    /// Where copies of the model are drawn. A single copy is drawn at the origin when empty.
+    pub instances: Vec<Placement>,
+    pub width: u32,
+    pub height: u32,
+    pub title: String,
+    pub clear_color: [f32; 4],
+    pub camera: CameraMode,
+    pub projection: Projection,
+    /// The vertical field of view in degrees.
+    pub fov: f32,
+    pub near: f32,
+    pub far: f32,
+    /// Never tear, presenting at most one frame per vertical blank.
+    pub vsync: bool,
+    /// How many frames can be submitted before the CPU waits for the oldest to complete.
+    pub frames_in_flight: usize,
+    /// Clamped to the highest count the device supports.
+    pub msaa_samples: u32,
+    /// The minimum fraction of samples shaded separately, reducing aliasing
+    /// inside triangles as well as along their edges. Disabled when unset or
+    /// unsupported by the device.
+    pub sample_shading: Option<f32>,
+    /// Renders with dynamic rendering and synchronization2 instead of render
+    /// pass objects where the device supports them.
+    pub dynamic_rendering: bool,
+    /// Appends the statistics of the last frame to the window title.
+    pub stats_in_title: bool,
+    /// Enables the validation layer, debug messenger and handle leak tracking.
+    pub validation: bool,
+    /// A physical device index, UUID or part of a name. The highest ranked device is used when unset.
+    pub device: Option<String>,
+    pub pipeline_cache: PathBuf,
+    /// Where GPU timings are written. The GPU is only profiled when set.
+    pub profile: Option<PathBuf>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            model: PathBuf::from("resources/viking_room.obj"),
+            texture: None,
+            instances: vec![],
+            width: 1024,
+            height: 768,
+            title: String::from("Vulkan Tutorial (Rust)"),
+            clear_color: [0.0, 0.0, 0.0, 1.0],
+            camera: CameraMode::Orbit,
+            projection: Projection::Perspective,
+            fov: 45.0,
+            near: 0.1,
+            far: 100.0,
+            vsync: true,
+            frames_in_flight: DEFAULT_FRAMES_IN_FLIGHT,
+            msaa_samples: 1,
+            sample_shading: None,
+            dynamic_rendering: true,
+            stats_in_title: false,
+            validation: VALIDATION_ENABLED,
+            device: None,
+            pipeline_cache: PathBuf::from("pipeline_cache.bin"),
+            profile: None,
+        }
+    }
+}
+
+impl Settings {
+    /// Reads the config file named by `args`, if any, and applies `args` over it.
+    pub fn load(args: &Args) -> Result<Self> {
+        let mut settings = match &args.config {
+            Some(path) => {
+                let contents = fs::read_to_string(path)
+                    .map_err(|e| anyhow!("Failed to read `{}`: {}", path.display(), e))?;
+                toml::from_str(&contents)
+                    .map_err(|e| anyhow!("Invalid config file `{}`: {}", path.display(), e))?
+            }
+            None => Self::default(),
+        };
+
+        let args = args.clone();
+        settings.model = args.model.unwrap_or(settings.model);
+        settings.texture = args.texture.or(settings.texture);
+        if !args.instances.is_empty() {
+            settings.instances = args.instances
+                .iter()
+                .map(|t| Placement { translation: *t, ..Default::default() })
+                .collect();
+        }
+        settings.width = args.width.unwrap_or(settings.width);
+        settings.height = args.height.unwrap_or(settings.height);
+        settings.title = args.title.unwrap_or(settings.title);
+        settings.clear_color = args.clear_color.unwrap_or(settings.clear_color);
+        settings.camera = args.camera.unwrap_or(settings.camera);
+        settings.projection = args.projection.unwrap_or(settings.projection);
+        settings.fov = args.fov.unwrap_or(settings.fov);
+        settings.near = args.near.unwrap_or(settings.near);
+        settings.far = args.far.unwrap_or(settings.far);
+        settings.vsync = args.vsync.unwrap_or(settings.vsync);
+        settings.frames_in_flight = args.frames_in_flight.unwrap_or(settings.frames_in_flight);
+        settings.msaa_samples = args.msaa_samples.unwrap_or(settings.msaa_samples);
+        settings.sample_shading = args.sample_shading.or(settings.sample_shading);
+        settings.dynamic_rendering = args.dynamic_rendering.unwrap_or(settings.dynamic_rendering);
+        settings.stats_in_title = args.stats_in_title.unwrap_or(settings.stats_in_title);
+        settings.validation = args.validation.unwrap_or(settings.validation);
+        settings.device = args.device.or(settings.device);
+        settings.pipeline_cache = args.pipeline_cache.unwrap_or(settings.pipeline_cache);
+        settings.profile = args.profile.or(settings.profile);
+
+        settings.validate()?;
+
+        Ok(settings)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.width == 0 || self.height == 0 {
+            return Err(anyhow!("Invalid resolution {}x{}.", self.width, self.height));
+        }
+
+        if !(self.fov > 0.0 && self.fov < 180.0) {
+            return Err(anyhow!("Invalid field of view {}, expected between 0 and 180 degrees.", self.fov));
+        }
+
+        if !(self.near > 0.0 && self.far > self.near) {
+            return Err(anyhow!("Invalid clipping planes {} and {}, expected 0 < near < far.", self.near, self.far));
+        }
+
+        if let Some(instance) = self.instances.iter().find(|i| !i.scale.is_finite() || i.scale <= 0.0) {
+            return Err(anyhow!("Invalid instance scale {}, expected a positive number.", instance.scale));
+        }
+
+        if !(1..=8).contains(&self.frames_in_flight) {
+            return Err(anyhow!("Invalid number of frames in flight {}, expected 1 to 8.", self.frames_in_flight));
+        }
+
+        if !self.msaa_samples.is_power_of_two() || self.msaa_samples > 64 {
+            return Err(anyhow!("Invalid MSAA sample count {}, expected 1, 2, 4, 8, 16, 32 or 64.", self.msaa_samples));
+        }
+
+        if let Some(fraction) = self.sample_shading {
+            if !(0.0..=1.0).contains(&fraction) {
+                return Err(anyhow!("Invalid sample shading fraction {}, expected 0 to 1.", fraction));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_color(value: &str) -> Result<[f32; 4], String> {
+    parse_components(value)
+}
+
+fn parse_position(value: &str) -> Result<[f32; 3], String> {
+    parse_components(value)
+}
+
+fn parse_components<const N: usize>(value: &str) -> Result<[f32; N], String> {
+    let components = value
+        .split(',')
+        .map(|c| c.trim().parse::<f32>().map_err(|e| e.to_string()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    components
+        .try_into()
+        .map_err(|c: Vec<_>| format!("expected {} components, got {}", N, c.len()))
+}
@@ -1,45 +1,36 @@
-use cgmath::{point3, vec3, Deg};
 use vulkanalia::{bytecode::Bytecode, vk::{self, DeviceV1_0, HasBuilder }, Device, Instance};
-use anyhow:: Result;
-use std::{mem::size_of, time::Instant};
+use anyhow::{anyhow, Result};
+use std::mem::size_of;
 
 
-use crate::{buffer::{copy_buffer, create_buffer}, mesh::{Mat4, Vertex}};
+use crate::{buffer::create_buffer, camera::Camera, mesh::Mat4};
 
 use crate::app_data::AppData;
+use crate::owned::{Owned, SharedDevice};
 
 
 use std::ptr::copy_nonoverlapping as memcpy;
 
-const CORRECTION : Mat4= Mat4::new(
-    1.0,  0.0,       0.0, 0.0,
-    // We're also flipping the Y-axis with this line's `-1.0`.
-    0.0, -1.0,       0.0, 0.0,
-    0.0,  0.0, 1.0 / 2.0, 0.0,
-    0.0,  0.0, 1.0 / 2.0, 1.0,
-);
-
-
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
 pub struct UniformBufferObject {
-    pub model: Mat4,
     pub view: Mat4,
     pub proj: Mat4,
 }
 
 
 
+#[track_caller]
 pub(crate) unsafe fn create_shader_module(
-    device: &Device,
+    device: &SharedDevice,
     bytecode: &[u8],
-) -> Result<vk::ShaderModule> {
+) -> Result<Owned<vk::ShaderModule>> {
     let bytecode = Bytecode::new(bytecode).unwrap();
     let info = vk::ShaderModuleCreateInfo::builder()
         .code_size(bytecode.code_size())
         .code(bytecode.code());
 
-    Ok(device.create_shader_module(&info, None)?)
+    Ok(Owned::new(device, device.create_shader_module(&info, None)?))
 }
 
 
@@ -47,126 +38,48 @@ pub(crate) unsafe fn create_shader_module(
 
 
 
-pub unsafe fn create_vertex_buffer(
-    instance: &Instance,
-    device: &Device,
-    data: &mut AppData,
-) -> Result<()> {
-    let size = (size_of::<Vertex>() * data.vertices.len()) as u64;
-
-    let (staging_buffer, staging_buffer_memory) = create_buffer(
-        instance, 
-        device,
-        data,
-        size,
-        vk::BufferUsageFlags::TRANSFER_SRC,
-        vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
-    )?;
-
-    let memory = device.map_memory(
-        staging_buffer_memory,
-        0,
-        size,
-        vk::MemoryMapFlags::empty(),
-    )?;
-
-    memcpy( data.vertices.as_ptr(), memory.cast(),  data.vertices.len());
-
-    device.unmap_memory(staging_buffer_memory);
-
-    let (vertex_buffer, vertex_buffer_memory) = create_buffer(
-        instance,
-        device,
-        data,
-        size,
-        vk::BufferUsageFlags::TRANSFER_DST | vk::BufferUsageFlags::VERTEX_BUFFER,
-        vk::MemoryPropertyFlags::DEVICE_LOCAL,
-    )?;
-
-    data.vertex_buffer = vertex_buffer;
-    data.vertex_buffer_memory = vertex_buffer_memory;
-    
-    copy_buffer(device, data, staging_buffer, vertex_buffer, size)?;
-
-    device.destroy_buffer(staging_buffer, None);
-    device.free_memory(staging_buffer_memory, None);
-
-    
-    Ok(())
-}
-
-
-pub unsafe fn create_index_buffer(
-    instance: &Instance,
-    device: &Device,
-    data: &mut AppData,
-) -> Result<()> {
-    let size = (size_of::<u32>() * data.indices.len()) as u64;
-
-    let (staging_buffer, staging_buffer_memory) = create_buffer(
-        instance,
-        device,
-        data,
-        size,
-        vk::BufferUsageFlags::TRANSFER_SRC,
-        vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
-    )?;
-
-    let memory = device.map_memory(
-        staging_buffer_memory,
-        0,
-        size,
-        vk::MemoryMapFlags::empty(),
-    )?;
-
-    memcpy(data.indices.as_ptr(), memory.cast(), data.indices.len());
-
-    device.unmap_memory(staging_buffer_memory);
-
-    let (index_buffer, index_buffer_memory) = create_buffer(
-        instance,
-        device,
-        data,
-        size,
-        vk::BufferUsageFlags::TRANSFER_DST | vk::BufferUsageFlags::INDEX_BUFFER,
-        vk::MemoryPropertyFlags::DEVICE_LOCAL,
-    )?;
-
-    data.index_buffer = index_buffer;
-    data.index_buffer_memory = index_buffer_memory;
-
-    copy_buffer(device, data, staging_buffer, index_buffer, size)?;
+/// The descriptor set bound once per frame, holding the camera uniforms.
+pub const FRAME_SET: u32 = 0;
+/// The descriptor set bound per draw, holding the instance's texture.
+pub const MATERIAL_SET: u32 = 1;
 
-    device.destroy_buffer(staging_buffer, None);
-    device.free_memory(staging_buffer_memory, None);
 
-    Ok(())
-}
-
-
-pub unsafe fn create_descriptor_set_layout(
-    device: &Device,
+/// Creates a layout for each descriptor set, indexed by set number.
+pub unsafe fn create_descriptor_set_layouts(
+    device: &SharedDevice,
     data: &mut AppData,
 ) -> Result<()> {
 
-    let ubo_binding = vk::DescriptorSetLayoutBinding::builder()
-        .binding(0)
-        .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
-        .descriptor_count(1)
-        .stage_flags(vk::ShaderStageFlags::VERTEX);
-
-    let sampler_binding = vk::DescriptorSetLayoutBinding::builder()
-        .binding(1)
-        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-        .descriptor_count(1)
-        .stage_flags(vk::ShaderStageFlags::FRAGMENT);
-    
+    if let Some(binding) = data.shader_reflection.bindings.iter().find(|b| b.set > MATERIAL_SET) {
+        return Err(anyhow!(
+            "Descriptor set {} is used but only sets {} (frame) and {} (material) are supported.",
+            binding.set,
+            FRAME_SET,
+            MATERIAL_SET,
+        ));
+    }
 
-    let bindings = &[ubo_binding,sampler_binding];
-    let info = vk::DescriptorSetLayoutCreateInfo::builder()
-        .bindings(bindings);
-        
-    data.descriptor_set_layout = device.create_descriptor_set_layout(&info, None)?;
+    data.descriptor_set_layouts = (0..=MATERIAL_SET)
+        .map(|set| {
+            let bindings = data.shader_reflection.bindings
+                .iter()
+                .filter(|b| b.set == set)
+                .map(|b| {
+                    vk::DescriptorSetLayoutBinding::builder()
+                        .binding(b.binding)
+                        .descriptor_type(b.type_)
+                        .descriptor_count(b.count)
+                        .stage_flags(b.stages)
+                        .build()
+                })
+                .collect::<Vec<_>>();
+
+            let info = vk::DescriptorSetLayoutCreateInfo::builder()
+                .bindings(&bindings);
+
+            Ok(Owned::new(device, device.create_descriptor_set_layout(&info, None)?))
+        })
+        .collect::<Result<Vec<_>>>()?;
 
 
     Ok(())
@@ -174,129 +87,161 @@ pub unsafe fn create_descriptor_set_layout(
 
 pub unsafe fn create_uniform_buffers(
     instance: &Instance,
-    device: &Device,
+    device: &SharedDevice,
     data: &mut AppData,
 ) -> Result<()> {
     data.uniform_buffers.clear();
-    data.uniform_buffers_memory.clear();
 
     for _ in 0..data.swapchain_images.len() {
-        let (uniform_buffer, uniform_buffer_memory) = create_buffer(
-            instance,
+        let uniform_buffer = create_buffer(
             device,
-            data,
             size_of::<UniformBufferObject>() as u64,
             vk::BufferUsageFlags::UNIFORM_BUFFER,
             vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
         )?;
 
         data.uniform_buffers.push(uniform_buffer);
-        data.uniform_buffers_memory.push(uniform_buffer_memory);
     }
 
     Ok(())
 }
 
-pub unsafe fn update_uniform_buffer(time: &Instant,device : &Device,data : &mut AppData, image_index: usize) -> Result<()> {
-        
-    let time = time.elapsed().as_secs_f32();
-
-    let model = Mat4::from_axis_angle(
-        vec3(0.0, 0.0, 1.0),
-        Deg(90.0) * time
-    );
-
-
-    let view = Mat4::look_at_rh(
-        point3(2.0, 2.0, 2.0),
-        point3(0.0, 0.0, 0.0),
-        vec3(0.0, 0.0, 1.0),
-    );
-
-    let proj = CORRECTION * cgmath::perspective(
-        Deg(45.0),
-        data.swapchain_extent.width as f32 / data.swapchain_extent.height as f32,
-        0.1,
-        10.0,
-    );
-
-
-    let ubo = UniformBufferObject { model, view, proj };
-    let memory = device.map_memory(
-        data.uniform_buffers_memory[image_index],
-        0,
-        size_of::<UniformBufferObject>() as u64,
-        vk::MemoryMapFlags::empty(),
-    )?;
-    
-    memcpy(&ubo, memory.cast(), 1);
+/// Writes the camera's view and projection to the uniform buffer of a swapchain image.
+pub unsafe fn update_uniform_buffer(camera: &Camera, data: &AppData, image_index: usize) -> Result<()> {
+    let aspect = data.swapchain_extent.width as f32 / data.swapchain_extent.height as f32;
+    let view = camera.view();
+    let proj = camera.proj(aspect);
+
+    let ubo = UniformBufferObject { view, proj };
     
-    device.unmap_memory(data.uniform_buffers_memory[image_index]);
+    memcpy(&ubo, data.uniform_buffers[image_index].memory().mapped_ptr()?.cast(), 1);
     
     Ok(())
 }
 
 
-pub unsafe fn create_descriptor_pool(device: &Device, data: &mut AppData) -> Result<()> {
-    let ubo_size = vk::DescriptorPoolSize::builder()
-        .type_(vk::DescriptorType::UNIFORM_BUFFER)
-        .descriptor_count(data.swapchain_images.len() as u32);
-
+/// Creates the pool the per-frame descriptor sets are allocated from.
+pub unsafe fn create_descriptor_pool(device: &SharedDevice, data: &mut AppData) -> Result<()> {
+    let pool_sizes = data.shader_reflection
+        .pool_sizes(FRAME_SET)
+        .into_iter()
+        .map(|s| vk::DescriptorPoolSize {
+            type_: s.type_,
+            descriptor_count: s.descriptor_count * data.swapchain_images.len() as u32,
+        })
+        .collect::<Vec<_>>();
 
-    let sampler_size = vk::DescriptorPoolSize::builder()
-        .type_(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-        .descriptor_count(data.swapchain_images.len() as u32);
-
-    let pool_sizes = &[ubo_size,sampler_size];
     let info = vk::DescriptorPoolCreateInfo::builder()
-        .pool_sizes(pool_sizes)
+        .pool_sizes(&pool_sizes)
         .max_sets(data.swapchain_images.len() as u32);
 
-    data.descriptor_pool = device.create_descriptor_pool(&info, None)?;
+    data.descriptor_pool = Owned::new(device, device.create_descriptor_pool(&info, None)?);
 
     Ok(())
 }
 
+/// Allocates and writes a frame descriptor set for each swapchain image.
 pub unsafe fn create_descriptor_sets(device: &Device, data: &mut AppData) -> Result<()> {
 
 
-    let layouts = vec![data.descriptor_set_layout; data.swapchain_images.len()];
+    let layouts = vec![*data.descriptor_set_layouts[FRAME_SET as usize]; data.swapchain_images.len()];
     let info = vk::DescriptorSetAllocateInfo::builder()
-        .descriptor_pool(data.descriptor_pool)
+        .descriptor_pool(*data.descriptor_pool)
         .set_layouts(&layouts);
     data.descriptor_sets = device.allocate_descriptor_sets(&info)?;
     for i in 0..data.swapchain_images.len() {
-        let info = vk::DescriptorBufferInfo::builder()
-            .buffer(data.uniform_buffers[i])
+        let buffer_info = &[vk::DescriptorBufferInfo::builder()
+            .buffer(*data.uniform_buffers[i])
             .offset(0)
-            .range(size_of::<UniformBufferObject>() as u64);
+            .range(size_of::<UniformBufferObject>() as u64)];
+
+        // Each binding the shaders declare is matched with the resource of that type.
+        let mut writes = vec![];
+        for binding in data.shader_reflection.bindings.iter().filter(|b| b.set == FRAME_SET) {
+            let write = vk::WriteDescriptorSet::builder()
+                .dst_set(data.descriptor_sets[i])
+                .dst_binding(binding.binding)
+                .dst_array_element(0)
+                .descriptor_type(binding.type_);
+
+            let write = match binding.type_ {
+                vk::DescriptorType::UNIFORM_BUFFER if binding.size as usize != size_of::<UniformBufferObject>() => {
+                    return Err(anyhow!(
+                        "Uniform buffer at binding {} is {} bytes but `UniformBufferObject` is {} bytes.",
+                        binding.binding,
+                        binding.size,
+                        size_of::<UniformBufferObject>(),
+                    ));
+                }
+                vk::DescriptorType::UNIFORM_BUFFER => write.buffer_info(buffer_info),
+                type_ => return Err(anyhow!("No frame resource for {:?} at binding {}.", type_, binding.binding)),
+            };
+
+            writes.push(write);
+        }
+
+        device.update_descriptor_sets(&writes, &[] as &[vk::CopyDescriptorSet]);
+    }
 
-        let buffer_info = &[info];
-        let ubo_write = vk::WriteDescriptorSet::builder()
-            .dst_set(data.descriptor_sets[i])
-            .dst_binding(0)
-            .dst_array_element(0)
-            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
-            .buffer_info(buffer_info);
+    Ok(())
+}
+
+/// Allocates and writes a material descriptor set for each texture in the scene.
+///
+/// Nothing is allocated if the shaders declare no material bindings.
+pub unsafe fn create_material_descriptor_sets(device: &SharedDevice, data: &mut AppData) -> Result<()> {
+    let count = data.scene.textures.len() as u32;
+    let pool_sizes = data.shader_reflection.pool_sizes(MATERIAL_SET);
+    if count == 0 || pool_sizes.is_empty() {
+        return Ok(());
+    }
 
+    let pool_sizes = pool_sizes
+        .into_iter()
+        .map(|s| vk::DescriptorPoolSize {
+            type_: s.type_,
+            descriptor_count: s.descriptor_count * count,
+        })
+        .collect::<Vec<_>>();
 
+    let info = vk::DescriptorPoolCreateInfo::builder()
+        .pool_sizes(&pool_sizes)
+        .max_sets(count);
+
+    data.scene.descriptor_pool = Owned::new(device, device.create_descriptor_pool(&info, None)?);
 
-        let info = vk::DescriptorImageInfo::builder()
+    let layouts = vec![*data.descriptor_set_layouts[MATERIAL_SET as usize]; count as usize];
+    let info = vk::DescriptorSetAllocateInfo::builder()
+        .descriptor_pool(*data.scene.descriptor_pool)
+        .set_layouts(&layouts);
+    let descriptor_sets = device.allocate_descriptor_sets(&info)?;
+
+    for (texture, descriptor_set) in data.scene.textures.iter_mut().zip(descriptor_sets) {
+        texture.descriptor_set = descriptor_set;
+
+        let image_info = &[vk::DescriptorImageInfo::builder()
             .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
-            .image_view(data.texture_image_view)
-            .sampler(data.texture_sampler);
-    
-        let image_info = &[info];
-        let sampler_write = vk::WriteDescriptorSet::builder()
-            .dst_set(data.descriptor_sets[i])
-            .dst_binding(1)
-            .dst_array_element(0)
-            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-            .image_info(image_info);
-        
-        device.update_descriptor_sets(&[ubo_write,sampler_write], &[] as &[vk::CopyDescriptorSet]);
+            .image_view(*texture.view)
+            .sampler(*data.scene.sampler)];
+
+        let mut writes = vec![];
+        for binding in data.shader_reflection.bindings.iter().filter(|b| b.set == MATERIAL_SET) {
+            let write = vk::WriteDescriptorSet::builder()
+                .dst_set(descriptor_set)
+                .dst_binding(binding.binding)
+                .dst_array_element(0)
+                .descriptor_type(binding.type_);
+
+            let write = match binding.type_ {
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER => write.image_info(image_info),
+                type_ => return Err(anyhow!("No material resource for {:?} at binding {}.", type_, binding.binding)),
+            };
+
+            writes.push(write);
+        }
+
+        device.update_descriptor_sets(&writes, &[] as &[vk::CopyDescriptorSet]);
     }
 
     Ok(())
 }
-
@@ -0,0 +1,181 @@
+//! Counts what each frame draws.
+//!
+//! Draws, triangles and descriptor binds are counted on the CPU while
+//! command buffers are recorded. Where the device supports pipeline
+//! statistics queries, the shader invocations the GPU ran are read back once
+//! the frame has completed.
+
+use std::fmt;
+
+use anyhow::Result;
+use vulkanalia::prelude::v1_0::*;
+
+use crate::app_data::AppData;
+use crate::capabilities::{DeviceRequirements, Feature};
+use crate::owned::{Owned, SharedDevice};
+
+
+/// The statistics queried, in the order the results are written.
+const PIPELINE_STATISTICS: vk::QueryPipelineStatisticFlags = vk::QueryPipelineStatisticFlags::from_bits_truncate(
+    vk::QueryPipelineStatisticFlags::VERTEX_SHADER_INVOCATIONS.bits()
+        | vk::QueryPipelineStatisticFlags::CLIPPING_INVOCATIONS.bits()
+        | vk::QueryPipelineStatisticFlags::FRAGMENT_SHADER_INVOCATIONS.bits(),
+);
+
+
+/// Pipeline statistics are queried where supported.
+pub fn declare_requirements(data: &AppData, requirements: &mut DeviceRequirements) {
+    requirements.request_feature(Feature::PipelineStatisticsQuery);
+}
+
+/// The commands recorded for a frame.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DrawCounts {
+    pub draws: u32,
+    pub triangles: u64,
+    pub descriptor_binds: u32,
+}
+
+impl DrawCounts {
+    /// Counts an indexed draw of a triangle list.
+    pub fn draw_indexed(&mut self, index_count: u32, instance_count: u32) {
+        self.draws += 1;
+        self.triangles += (index_count / 3) as u64 * instance_count as u64;
+    }
+
+    pub fn bind_descriptor_sets(&mut self) {
+        self.descriptor_binds += 1;
+    }
+}
+
+/// What the GPU processed for a frame.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PipelineStatistics {
+    pub vertex_invocations: u64,
+    /// The primitives that reached the clipping stage.
+    pub primitives: u64,
+    pub fragment_invocations: u64,
+}
+
+/// The statistics of the most recent frames.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FrameStats {
+    /// Counted while recording the last frame.
+    pub counts: DrawCounts,
+    /// From the last completed frame, if pipeline statistics are supported.
+    pub pipeline: Option<PipelineStatistics>,
+}
+
+impl fmt::Display for FrameStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} draws, {} triangles, {} descriptor binds",
+            self.counts.draws,
+            self.counts.triangles,
+            self.counts.descriptor_binds,
+        )?;
+
+        if let Some(pipeline) = &self.pipeline {
+            write!(
+                f,
+                " | {} vertices, {} primitives, {} fragments",
+                pipeline.vertex_invocations,
+                pipeline.primitives,
+                pipeline.fragment_invocations,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A pipeline statistics query around each frame in flight.
+///
+/// The default value queries nothing and records no commands.
+#[derive(Clone, Debug, Default)]
+pub struct StatisticsQueries {
+    /// One per frame in flight, with a single query.
+    query_pools: Vec<Owned<vk::QueryPool>>,
+    /// Whether each frame in flight has a query result that hasn't been read.
+    pending: Vec<bool>,
+}
+
+/// Creates the pipeline statistics queries if the feature is enabled.
+pub unsafe fn create_statistics_queries(device: &SharedDevice, data: &mut AppData) -> Result<()> {
+    if !data.capabilities.has_feature(Feature::PipelineStatisticsQuery) {
+        return Ok(());
+    }
+
+    let info = vk::QueryPoolCreateInfo::builder()
+        .query_type(vk::QueryType::PIPELINE_STATISTICS)
+        .query_count(1)
+        .pipeline_statistics(PIPELINE_STATISTICS);
+
+    let query_pools = (0..data.settings.frames_in_flight)
+        .map(|_| Ok(Owned::new(device, device.create_query_pool(&info, None)?)))
+        .collect::<Result<Vec<_>>>()?;
+
+    data.statistics = StatisticsQueries { pending: vec![false; query_pools.len()], query_pools };
+
+    Ok(())
+}
+
+impl StatisticsQueries {
+    pub fn is_enabled(&self) -> bool {
+        !self.query_pools.is_empty()
+    }
+
+    /// Resets and begins the query of a frame in flight.
+    ///
+    /// Must be recorded before any render pass begins.
+    pub unsafe fn begin(&self, device: &Device, command_buffer: vk::CommandBuffer, frame: usize) {
+        if let Some(pool) = self.query_pools.get(frame) {
+            device.cmd_reset_query_pool(command_buffer, **pool, 0, 1);
+            device.cmd_begin_query(command_buffer, **pool, 0, vk::QueryControlFlags::empty());
+        }
+    }
+
+    /// Ends the query of a frame in flight, after every render pass has ended.
+    pub unsafe fn end(&self, device: &Device, command_buffer: vk::CommandBuffer, frame: usize) {
+        if let Some(pool) = self.query_pools.get(frame) {
+            device.cmd_end_query(command_buffer, **pool, 0);
+        }
+    }
+
+    /// Notes that a frame in flight has been submitted with a query to read.
+    pub fn frame_submitted(&mut self, frame: usize) {
+        if let Some(pending) = self.pending.get_mut(frame) {
+            *pending = true;
+        }
+    }
+
+    /// Reads the query of a frame in flight whose submission has completed.
+    ///
+    /// Returns `None` if the frame has no result to read.
+    pub unsafe fn read_frame(&mut self, device: &Device, frame: usize) -> Result<Option<PipelineStatistics>> {
+        if !self.pending.get(frame).copied().unwrap_or(false) {
+            return Ok(None);
+        }
+
+        self.pending[frame] = false;
+
+        let mut results = [0u64; 3];
+        let result = device.get_query_pool_results(
+            *self.query_pools[frame],
+            0,
+            1,
+            std::slice::from_raw_parts_mut(results.as_mut_ptr().cast(), 8 * results.len()),
+            8 * results.len() as vk::DeviceSize,
+            vk::QueryResultFlags::_64,
+        )?;
+
+        // The frame has completed, so this only happens if it was never recorded.
+        if result == vk::SuccessCode::NOT_READY {
+            return Ok(None);
+        }
+
+        let [vertex_invocations, primitives, fragment_invocations] = results;
+        Ok(Some(PipelineStatistics { vertex_invocations, primitives, fragment_invocations }))
+    }
+}
@@ -1,17 +1,26 @@
 
 use crate::{image::create_image_view, queue_family::QueueFamilyIndices};
 use crate::app_data::AppData;
+use crate::capabilities::DeviceRequirements;
+use crate::owned::{Owned, SharedDevice};
 
 
 use winit::window::Window;
 
-use vulkanalia::{vk::{self, Handle, HasBuilder, KhrSurfaceExtension, KhrSwapchainExtension}, Instance, Device};
+use vulkanalia::{vk::{self, HasBuilder, KhrSurfaceExtension, KhrSwapchainExtension}, Instance};
 
 use anyhow::Result;
 
 
 
 
+/// Presenting needs the swapchain extension, unless rendering headless.
+pub fn declare_requirements(data: &AppData, requirements: &mut DeviceRequirements) {
+    if !data.headless {
+        requirements.require_extension(vk::KHR_SWAPCHAIN_EXTENSION.name);
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct SwapchainSupport {
     pub capabilities: vk::SurfaceCapabilitiesKHR,
@@ -53,13 +62,21 @@ pub fn get_swapchain_surface_format(
 
 }
 
+/// Prefers mailbox with vsync and immediate without, falling back to FIFO which is always supported.
 pub fn get_swapchain_present_mode(
     present_modes: &[vk::PresentModeKHR],
+    vsync: bool,
 ) -> vk::PresentModeKHR {
-    present_modes
+    let preferred: &[_] = if vsync {
+        &[vk::PresentModeKHR::MAILBOX]
+    } else {
+        &[vk::PresentModeKHR::IMMEDIATE, vk::PresentModeKHR::MAILBOX]
+    };
+
+    preferred
         .iter()
         .cloned()
-        .find(|m| *m == vk::PresentModeKHR::MAILBOX)
+        .find(|m| present_modes.contains(m))
         .unwrap_or(vk::PresentModeKHR::FIFO)
 }
 
@@ -90,14 +107,14 @@ pub fn get_swapchain_extent(
 pub unsafe fn create_swapchain(
     window: &Window,
     instance: &Instance,
-    device: &Device,
+    device: &SharedDevice,
     data: &mut AppData,
 ) -> Result<()> {
     let indices = QueueFamilyIndices::get(instance, data, data.physical_device)?;
     let support = SwapchainSupport::get(instance, data, data.physical_device)?;
 
     let surface_format = get_swapchain_surface_format(&support.formats);
-    let present_mode = get_swapchain_present_mode(&support.present_modes);
+    let present_mode = get_swapchain_present_mode(&support.present_modes, data.settings.vsync);
     let extent = get_swapchain_extent(window, support.capabilities);
 
 
@@ -130,10 +147,11 @@ pub unsafe fn create_swapchain(
         .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
         .present_mode(present_mode)
         .clipped(true)
-        .old_swapchain(vk::SwapchainKHR::null());
+        .old_swapchain(*data.swapchain);
 
-    data.swapchain = device.create_swapchain_khr(&info, None)?;
-    data.swapchain_images = device.get_swapchain_images_khr(data.swapchain)?;
+    // The old swapchain is retired, and destroyed once nothing refers to it.
+    data.swapchain = Owned::new(device, device.create_swapchain_khr(&info, None)?);
+    data.swapchain_images = device.get_swapchain_images_khr(*data.swapchain)?;
     data.swapchain_format = surface_format.format;
     data.swapchain_extent = extent;
 
@@ -145,15 +163,18 @@ pub unsafe fn create_swapchain(
 
 
 pub unsafe fn create_swapchain_image_views(
-    device: &Device,
+    device: &SharedDevice,
     data: &mut AppData,
 ) -> Result<()> {
 
     data.swapchain_image_views = data
         .swapchain_images
         .iter()
-        .map(|i| create_image_view(device, *i, data.swapchain_format,  1, vk::ImageAspectFlags::COLOR))
-        .collect::<Result<Vec<_>, _>>()?;
+        .map(|i| {
+            let view = create_image_view(device, *i, data.swapchain_format,  1, vk::ImageAspectFlags::COLOR)?;
+            Ok(view.depends_on(&data.swapchain))
+        })
+        .collect::<Result<Vec<_>>>()?;
     Ok(())
 }
 
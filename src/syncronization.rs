@@ -1,28 +1,259 @@
+//! Paces frames in flight and tracks when the GPU has finished with them.
+//!
+//! Every submission to the graphics queue is given an increasing value. Where
+//! timeline semaphores are supported a single timeline semaphore is signaled
+//! with that value, and otherwise each frame in flight has a fence. Either
+//! way per-frame resources, uploads and deferred deletions can be tied to a
+//! value and reused once it has completed.
+
+use std::any::Any;
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::rc::Rc;
 
 use crate::app_data::AppData;
+use crate::capabilities::{DeviceRequirements, Feature};
+use crate::owned::{Owned, SharedDevice};
 
 use anyhow::Result;
-use vulkanalia::{vk::{self, DeviceV1_0, Handle, HasBuilder}, Device};
+use vulkanalia::prelude::v1_0::*;
+use vulkanalia::vk::DeviceV1_2;
+
+
+pub const DEFAULT_FRAMES_IN_FLIGHT: usize = 2;
+
+
+/// Frames are paced with a timeline semaphore where supported.
+pub fn declare_requirements(data: &AppData, requirements: &mut DeviceRequirements) {
+    requirements.request_feature(Feature::TimelineSemaphore);
+}
+
+/// A timeline semaphore signaled by submissions to a queue, in order.
+///
+/// The default value is a null semaphore, used when timeline semaphores aren't supported.
+#[derive(Clone, Debug, Default)]
+pub struct Timeline {
+    semaphore: Owned<vk::Semaphore>,
+    /// The value the last submission signals, shared by every clone.
+    submitted: Rc<Cell<u64>>,
+}
+
+impl Timeline {
+    pub unsafe fn new(device: &SharedDevice) -> Result<Self> {
+        let mut type_info = vk::SemaphoreTypeCreateInfo::builder()
+            .semaphore_type(vk::SemaphoreType::TIMELINE)
+            .initial_value(0);
+        let info = vk::SemaphoreCreateInfo::builder().push_next(&mut type_info);
+
+        let semaphore = Owned::new(device, device.create_semaphore(&info, None)?);
+        Ok(Self { semaphore, submitted: Rc::default() })
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.semaphore.is_null()
+    }
+
+    pub fn semaphore(&self) -> vk::Semaphore {
+        *self.semaphore
+    }
+
+    /// Reserves the value for a submission, which must signal it.
+    pub fn next_value(&self) -> u64 {
+        self.submitted.set(self.submitted.get() + 1);
+        self.submitted.get()
+    }
+
+    /// The value of the last submission.
+    pub fn submitted(&self) -> u64 {
+        self.submitted.get()
+    }
+
+    /// The value of the last completed submission.
+    pub unsafe fn completed(&self, device: &Device) -> Result<u64> {
+        Ok(device.get_semaphore_counter_value(*self.semaphore)?)
+    }
+
+    pub unsafe fn wait(&self, device: &Device, value: u64) -> Result<()> {
+        let semaphores = &[*self.semaphore];
+        let values = &[value];
+        let info = vk::SemaphoreWaitInfo::builder()
+            .semaphores(semaphores)
+            .values(values);
+
+        device.wait_semaphores(&info, u64::MAX)?;
+        Ok(())
+    }
+}
+
+/// Tracks the submissions of the frames in flight.
+///
+/// A frame in flight can be recorded again once its previous submission has
+/// completed, and a swapchain image once the last frame rendering to it has.
+#[derive(Clone, Debug, Default)]
+pub struct FrameSync {
+    /// Null when timeline semaphores aren't supported.
+    pub timeline: Timeline,
+    /// One per frame in flight, only used without a timeline semaphore.
+    fences: Vec<Owned<vk::Fence>>,
+    /// The value of the last submission of each frame in flight.
+    frame_values: Vec<u64>,
+    /// The value of the last submission rendering to each swapchain image.
+    image_values: Vec<u64>,
+    /// The value of the last submission, without a timeline semaphore.
+    submitted: u64,
+    /// Kept alive until the submission that was last when they were deferred completes.
+    deletions: VecDeque<(u64, Rc<dyn Any>)>,
+}
+
+impl FrameSync {
+    /// Waits for the previous submission of a frame in flight, so it can be
+    /// recorded again, and drops the deferred deletions that have completed.
+    pub unsafe fn begin_frame(&mut self, device: &Device, frame: usize) -> Result<()> {
+        self.wait(device, self.frame_values[frame])?;
+
+        let completed = self.completed(device)?;
+        while self.deletions.front().is_some_and(|(value, _)| *value <= completed) {
+            self.deletions.pop_front();
+        }
+
+        Ok(())
+    }
+
+    /// Waits for the last submission rendering to a swapchain image.
+    pub unsafe fn wait_for_image(&self, device: &Device, image_index: usize) -> Result<()> {
+        self.wait(device, self.image_values[image_index])
+    }
+
+    /// Submits the command buffers of a frame in flight rendering to a
+    /// swapchain image, returning the value of the submission.
+    pub unsafe fn submit(
+        &mut self,
+        device: &Device,
+        queue: vk::Queue,
+        frame: usize,
+        image_index: usize,
+        command_buffers: &[vk::CommandBuffer],
+        wait: &[(vk::Semaphore, vk::PipelineStageFlags)],
+        signal: &[vk::Semaphore],
+    ) -> Result<u64> {
+        let (wait_semaphores, wait_stages): (Vec<_>, Vec<_>) = wait.iter().cloned().unzip();
+        let mut signal_semaphores = signal.to_vec();
+
+        let (value, fence) = if self.timeline.is_null() {
+            self.submitted += 1;
+            device.reset_fences(&[*self.fences[frame]])?;
+            (self.submitted, *self.fences[frame])
+        } else {
+            signal_semaphores.push(self.timeline.semaphore());
+            (self.timeline.next_value(), vk::Fence::null())
+        };
+
+        // Values are ignored for binary semaphores.
+        let wait_values = vec![0; wait_semaphores.len()];
+        let mut signal_values = vec![0; signal.len()];
+        signal_values.push(value);
+
+        let mut timeline_info = vk::TimelineSemaphoreSubmitInfo::builder()
+            .wait_semaphore_values(&wait_values)
+            .signal_semaphore_values(&signal_values);
+
+        let mut info = vk::SubmitInfo::builder()
+            .wait_semaphores(&wait_semaphores)
+            .wait_dst_stage_mask(&wait_stages)
+            .command_buffers(command_buffers)
+            .signal_semaphores(&signal_semaphores);
+
+        if !self.timeline.is_null() {
+            info = info.push_next(&mut timeline_info);
+        }
+
+        device.queue_submit(queue, &[info], fence)?;
+
+        self.frame_values[frame] = value;
+        self.image_values[image_index] = value;
+
+        Ok(value)
+    }
 
+    /// The value of the last submission.
+    pub fn submitted(&self) -> u64 {
+        if self.timeline.is_null() {
+            self.submitted
+        } else {
+            self.timeline.submitted()
+        }
+    }
+
+    /// The value of the last completed submission.
+    pub unsafe fn completed(&self, device: &Device) -> Result<u64> {
+        if !self.timeline.is_null() {
+            return self.timeline.completed(device);
+        }
 
-pub const MAX_FRAMES_IN_FLIGHT: usize = 2;
+        // Everything before the oldest incomplete frame has completed.
+        let mut completed = self.submitted;
+        for (fence, value) in self.fences.iter().zip(&self.frame_values) {
+            if device.get_fence_status(**fence)? == vk::SuccessCode::NOT_READY {
+                completed = completed.min(value - 1);
+            }
+        }
+
+        Ok(completed)
+    }
 
+    /// Waits for a submission to complete.
+    pub unsafe fn wait(&self, device: &Device, value: u64) -> Result<()> {
+        if !self.timeline.is_null() {
+            return self.timeline.wait(device, value);
+        }
 
+        // A frame in flight is only submitted again once its previous
+        // submission has completed, so older values have completed too.
+        if let Some(frame) = self.frame_values.iter().position(|v| *v == value && value > 0) {
+            device.wait_for_fences(&[*self.fences[frame]], true, u64::MAX)?;
+        }
 
-pub unsafe fn create_sync_objects(device: &Device, data: &mut AppData) -> Result<()> {
+        Ok(())
+    }
+
+    /// Keeps a resource alive until everything submitted so far has completed.
+    pub fn defer_drop<T: Any>(&mut self, resource: T) {
+        self.deletions.push_back((self.submitted(), Rc::new(resource)));
+    }
+
+    /// Forgets which submissions rendered to the swapchain images, once they
+    /// have all completed and the swapchain has been recreated.
+    pub fn reset_images(&mut self, image_count: usize) {
+        self.image_values = vec![0; image_count];
+    }
+}
+
+/// Creates the semaphores the swapchain is synchronized with and the
+/// timeline semaphore, or fences, frames are paced with.
+pub unsafe fn create_sync_objects(device: &SharedDevice, data: &mut AppData) -> Result<()> {
     let semaphore_info = vk::SemaphoreCreateInfo::builder();
     let fence_info = vk::FenceCreateInfo::builder().flags(vk::FenceCreateFlags::SIGNALED);
+    let frames_in_flight = data.settings.frames_in_flight;
 
-    for _ in 0..MAX_FRAMES_IN_FLIGHT {
+    for _ in 0..frames_in_flight {
         data.image_available_semaphores
-            .push(device.create_semaphore(&semaphore_info, None)?);
+            .push(Owned::new(device, device.create_semaphore(&semaphore_info, None)?));
         data.render_finished_semaphores
-            .push(device.create_semaphore(&semaphore_info, None)?);
-
-        data.in_flight_fences.push(device.create_fence(&fence_info, None)?);
+            .push(Owned::new(device, device.create_semaphore(&semaphore_info, None)?));
     }
 
-    data.images_in_flight = data.swapchain_images.iter().map(|_| vk::Fence::null()).collect();
+    data.frame_sync = if data.capabilities.has_feature(Feature::TimelineSemaphore) {
+        FrameSync { timeline: Timeline::new(device)?, ..Default::default() }
+    } else {
+        let fences = (0..frames_in_flight)
+            .map(|_| Ok(Owned::new(device, device.create_fence(&fence_info, None)?)))
+            .collect::<Result<Vec<_>>>()?;
+
+        FrameSync { fences, ..Default::default() }
+    };
+
+    data.frame_sync.frame_values = vec![0; frames_in_flight];
+    data.frame_sync.reset_images(data.swapchain_images.len());
 
     Ok(())
-}
\ No newline at end of file
+}
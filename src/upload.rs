@@ -0,0 +1,410 @@
+//! Batches the uploads of many resources into a single submission.
+
+use std::ptr::copy_nonoverlapping as memcpy;
+
+use anyhow::Result;
+use vulkanalia::prelude::v1_0::*;
+
+use crate::app_data::AppData;
+use crate::buffer::create_buffer;
+use crate::owned::{Owned, SharedDevice};
+use crate::queue_family::QueueFamilyIndices;
+use crate::syncronization::Timeline;
+
+
+/// The initial size of the staging buffer, which grows to fit larger resources.
+const STAGING_SIZE: vk::DeviceSize = 8 * 1024 * 1024;
+
+/// The alignment of data in the staging buffer, which satisfies the offset
+/// requirements of buffer and image copies on any queue.
+const STAGING_ALIGNMENT: vk::DeviceSize = 16;
+
+
+/// Identifies a submitted batch of uploads.
+///
+/// With a timeline semaphore this is the value the batch signals, and
+/// otherwise the number of batches submitted so far.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct UploadTicket(u64);
+
+/// Records copies and barriers for many resources into one transfer command
+/// buffer and one graphics command buffer, submitted together by
+/// [`UploadContext::flush`].
+///
+/// Data is staged in a persistent buffer that is reused by every batch, so
+/// only one batch is in flight at a time: staging data for a new batch waits
+/// for the previous one to complete.
+///
+/// When the transfer and graphics queues belong to different families every
+/// uploaded resource is released by the transfer queue and acquired by the
+/// graphics queue. Since the acquire barriers are submitted to the graphics
+/// queue, frames submitted afterwards can use the resources without waiting
+/// on the batch.
+///
+/// Where timeline semaphores are supported batches signal the same timeline
+/// as frames instead of a fence of their own.
+#[derive(Clone, Debug, Default)]
+pub struct UploadContext {
+    transfer_queue: vk::Queue,
+    graphics_queue: vk::Queue,
+    /// [`vk::QUEUE_FAMILY_IGNORED`] when both queues share a family.
+    transfer_family: u32,
+    graphics_family: u32,
+
+    transfer_pool: Owned<vk::CommandPool>,
+    graphics_pool: Owned<vk::CommandPool>,
+    transfer_commands: vk::CommandBuffer,
+    graphics_commands: vk::CommandBuffer,
+    /// Signaled by the transfer commands and waited on by the graphics commands.
+    semaphore: Owned<vk::Semaphore>,
+    /// Signaled when the last submitted batch completes, only used without a timeline semaphore.
+    fence: Owned<vk::Fence>,
+    timeline: Timeline,
+
+    staging_buffer: Owned<vk::Buffer>,
+    staging_size: vk::DeviceSize,
+    staging_offset: vk::DeviceSize,
+
+    recording: bool,
+    submitted: u64,
+}
+
+/// Creates the upload context with an empty staging buffer.
+pub unsafe fn create_upload_context(device: &SharedDevice, data: &mut AppData) -> Result<()> {
+    let QueueFamilyIndices { graphics, transfer, .. } = data.queue_families;
+
+    let create_pool = |family| -> Result<Owned<vk::CommandPool>> {
+        let info = vk::CommandPoolCreateInfo::builder()
+            .flags(vk::CommandPoolCreateFlags::TRANSIENT)
+            .queue_family_index(family);
+
+        Ok(Owned::new(device, device.create_command_pool(&info, None)?))
+    };
+
+    let allocate = |pool: &Owned<vk::CommandPool>| -> Result<vk::CommandBuffer> {
+        let info = vk::CommandBufferAllocateInfo::builder()
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_pool(**pool)
+            .command_buffer_count(1);
+
+        Ok(device.allocate_command_buffers(&info)?[0])
+    };
+
+    let transfer_pool = create_pool(transfer)?;
+    let graphics_pool = create_pool(graphics)?;
+
+    // Barriers with matching family indices are ordinary barriers.
+    let (transfer_family, graphics_family) = if transfer == graphics {
+        (vk::QUEUE_FAMILY_IGNORED, vk::QUEUE_FAMILY_IGNORED)
+    } else {
+        (transfer, graphics)
+    };
+
+    // The fence starts signaled so the first batch doesn't wait for a previous one.
+    let fence_info = vk::FenceCreateInfo::builder().flags(vk::FenceCreateFlags::SIGNALED);
+    let timeline = data.frame_sync.timeline.clone();
+    let fence = if timeline.is_null() {
+        Owned::new(device, device.create_fence(&fence_info, None)?)
+    } else {
+        Owned::default()
+    };
+
+    data.uploads = UploadContext {
+        transfer_queue: data.transfer_queue,
+        graphics_queue: data.graphics_queue,
+        transfer_family,
+        graphics_family,
+        transfer_commands: allocate(&transfer_pool)?,
+        graphics_commands: allocate(&graphics_pool)?,
+        transfer_pool,
+        graphics_pool,
+        semaphore: Owned::new(device, device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None)?),
+        fence,
+        timeline,
+        staging_buffer: create_staging_buffer(device, STAGING_SIZE)?,
+        staging_size: STAGING_SIZE,
+        staging_offset: 0,
+        recording: false,
+        submitted: 0,
+    };
+
+    Ok(())
+}
+
+impl UploadContext {
+    /// Copies data into the staging buffer, returning the buffer and the offset of the data.
+    ///
+    /// If the data doesn't fit in what is left of the staging buffer the
+    /// current batch is flushed first, so a resource should stage its data
+    /// before recording any commands.
+    pub unsafe fn stage(&mut self, device: &SharedDevice, bytes: &[u8]) -> Result<(vk::Buffer, vk::DeviceSize)> {
+        let size = bytes.len() as vk::DeviceSize;
+
+        if self.recording && self.staging_offset + size > self.staging_size {
+            self.flush(device)?;
+        }
+
+        self.begin(device)?;
+
+        // Nothing is staged in a new batch and the previous one has completed,
+        // so the staging buffer can be replaced.
+        if size > self.staging_size {
+            self.staging_size = size.next_power_of_two();
+            self.staging_buffer = create_staging_buffer(device, self.staging_size)?;
+        }
+
+        let offset = self.staging_offset;
+        let destination = self.staging_buffer.memory().mapped_ptr()?.cast::<u8>().add(offset as usize);
+        memcpy(bytes.as_ptr(), destination, bytes.len());
+
+        self.staging_offset = (offset + size).next_multiple_of(STAGING_ALIGNMENT);
+
+        Ok((*self.staging_buffer, offset))
+    }
+
+    /// The command buffer recorded on the transfer queue.
+    pub unsafe fn transfer_commands(&mut self, device: &Device) -> Result<vk::CommandBuffer> {
+        self.begin(device)?;
+        Ok(self.transfer_commands)
+    }
+
+    /// The command buffer recorded on the graphics queue once the transfer commands have completed.
+    pub unsafe fn graphics_commands(&mut self, device: &Device) -> Result<vk::CommandBuffer> {
+        self.begin(device)?;
+        Ok(self.graphics_commands)
+    }
+
+    /// Stages data and copies it into the start of a buffer, making it
+    /// available to the graphics queue at `dst_stages` for `dst_access`.
+    pub unsafe fn upload_buffer(
+        &mut self,
+        device: &SharedDevice,
+        bytes: &[u8],
+        buffer: vk::Buffer,
+        dst_stages: vk::PipelineStageFlags,
+        dst_access: vk::AccessFlags,
+    ) -> Result<()> {
+        let (staging_buffer, offset) = self.stage(device, bytes)?;
+
+        let region = vk::BufferCopy::builder()
+            .src_offset(offset)
+            .dst_offset(0)
+            .size(bytes.len() as vk::DeviceSize);
+
+        let command_buffer = self.transfer_commands(device)?;
+        device.cmd_copy_buffer(command_buffer, staging_buffer, buffer, &[region]);
+        self.release_buffer(device, buffer, dst_stages, dst_access);
+
+        Ok(())
+    }
+
+    /// Hands a buffer written by the transfer commands over to the graphics queue.
+    pub unsafe fn release_buffer(
+        &self,
+        device: &Device,
+        buffer: vk::Buffer,
+        dst_stages: vk::PipelineStageFlags,
+        dst_access: vk::AccessFlags,
+    ) {
+        let barrier = |src_access, dst_access| {
+            vk::BufferMemoryBarrier::builder()
+                .src_access_mask(src_access)
+                .dst_access_mask(dst_access)
+                .src_queue_family_index(self.transfer_family)
+                .dst_queue_family_index(self.graphics_family)
+                .buffer(buffer)
+                .offset(0)
+                .size(vk::WHOLE_SIZE as u64)
+                .build()
+        };
+
+        self.record_ownership_transfer(
+            dst_stages,
+            dst_access,
+            |cb, src_stages, dst_stages, src_access, dst_access| device.cmd_pipeline_barrier(
+                cb,
+                src_stages,
+                dst_stages,
+                vk::DependencyFlags::empty(),
+                &[] as &[vk::MemoryBarrier],
+                &[barrier(src_access, dst_access)],
+                &[] as &[vk::ImageMemoryBarrier],
+            ),
+        );
+    }
+
+    /// Hands an image written by the transfer commands over to the graphics
+    /// queue, transitioning it from `old_layout` to `new_layout`.
+    pub unsafe fn release_image(
+        &self,
+        device: &Device,
+        image: vk::Image,
+        subresource: vk::ImageSubresourceRange,
+        old_layout: vk::ImageLayout,
+        new_layout: vk::ImageLayout,
+        dst_stages: vk::PipelineStageFlags,
+        dst_access: vk::AccessFlags,
+    ) {
+        let barrier = |src_access, dst_access| {
+            vk::ImageMemoryBarrier::builder()
+                .src_access_mask(src_access)
+                .dst_access_mask(dst_access)
+                .old_layout(old_layout)
+                .new_layout(new_layout)
+                .src_queue_family_index(self.transfer_family)
+                .dst_queue_family_index(self.graphics_family)
+                .image(image)
+                .subresource_range(subresource)
+                .build()
+        };
+
+        self.record_ownership_transfer(
+            dst_stages,
+            dst_access,
+            |cb, src_stages, dst_stages, src_access, dst_access| device.cmd_pipeline_barrier(
+                cb,
+                src_stages,
+                dst_stages,
+                vk::DependencyFlags::empty(),
+                &[] as &[vk::MemoryBarrier],
+                &[] as &[vk::BufferMemoryBarrier],
+                &[barrier(src_access, dst_access)],
+            ),
+        );
+    }
+
+    /// Submits the current batch, if anything has been recorded, returning a
+    /// ticket that completes along with it.
+    pub unsafe fn flush(&mut self, device: &Device) -> Result<UploadTicket> {
+        if !self.recording {
+            return Ok(UploadTicket(self.submitted));
+        }
+
+        device.end_command_buffer(self.transfer_commands)?;
+        device.end_command_buffer(self.graphics_commands)?;
+
+        let transfer_command_buffers = &[self.transfer_commands];
+        let signal_semaphores = &[*self.semaphore];
+        let transfer_info = vk::SubmitInfo::builder()
+            .command_buffers(transfer_command_buffers)
+            .signal_semaphores(signal_semaphores);
+
+        device.queue_submit(self.transfer_queue, &[transfer_info], vk::Fence::null())?;
+
+        let graphics_command_buffers = &[self.graphics_commands];
+        let wait_stages = &[vk::PipelineStageFlags::ALL_COMMANDS];
+        let mut graphics_info = vk::SubmitInfo::builder()
+            .wait_semaphores(signal_semaphores)
+            .wait_dst_stage_mask(wait_stages)
+            .command_buffers(graphics_command_buffers);
+
+        let (ticket, fence) = if self.timeline.is_null() {
+            device.reset_fences(&[*self.fence])?;
+            (self.submitted + 1, *self.fence)
+        } else {
+            (self.timeline.next_value(), vk::Fence::null())
+        };
+
+        let timeline_semaphores = &[self.timeline.semaphore()];
+        let wait_values = &[0];
+        let signal_values = &[ticket];
+        let mut timeline_info = vk::TimelineSemaphoreSubmitInfo::builder()
+            .wait_semaphore_values(wait_values)
+            .signal_semaphore_values(signal_values);
+
+        if !self.timeline.is_null() {
+            graphics_info = graphics_info
+                .signal_semaphores(timeline_semaphores)
+                .push_next(&mut timeline_info);
+        }
+
+        device.queue_submit(self.graphics_queue, &[graphics_info], fence)?;
+
+        self.recording = false;
+        self.submitted = ticket;
+
+        Ok(UploadTicket(self.submitted))
+    }
+
+    /// Checks whether a batch has completed without waiting for it.
+    pub unsafe fn is_complete(&self, device: &Device, ticket: UploadTicket) -> Result<bool> {
+        if !self.timeline.is_null() {
+            Ok(ticket.0 <= self.submitted && self.timeline.completed(device)? >= ticket.0)
+        } else if ticket.0 < self.submitted {
+            // Older batches are waited on before the next one begins.
+            Ok(true)
+        } else if ticket.0 == self.submitted {
+            Ok(device.get_fence_status(*self.fence)? == vk::SuccessCode::SUCCESS)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Waits for a batch to complete, submitting it first if it is still being recorded.
+    pub unsafe fn wait(&mut self, device: &Device, ticket: UploadTicket) -> Result<()> {
+        if ticket.0 > self.submitted {
+            self.flush(device)?;
+        }
+
+        if !self.timeline.is_null() {
+            self.timeline.wait(device, ticket.0)?;
+        } else if ticket.0 == self.submitted {
+            device.wait_for_fences(&[*self.fence], true, u64::MAX)?;
+        }
+
+        Ok(())
+    }
+
+    /// Starts a new batch once the previous one has completed.
+    unsafe fn begin(&mut self, device: &Device) -> Result<()> {
+        if self.recording {
+            return Ok(());
+        }
+
+        self.wait(device, UploadTicket(self.submitted))?;
+        device.reset_command_pool(*self.transfer_pool, vk::CommandPoolResetFlags::empty())?;
+        device.reset_command_pool(*self.graphics_pool, vk::CommandPoolResetFlags::empty())?;
+
+        let info = vk::CommandBufferBeginInfo::builder()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+
+        device.begin_command_buffer(self.transfer_commands, &info)?;
+        device.begin_command_buffer(self.graphics_commands, &info)?;
+
+        self.staging_offset = 0;
+        self.recording = true;
+
+        Ok(())
+    }
+
+    /// Records the release half of a barrier on the transfer queue and the
+    /// acquire half on the graphics queue, or a single ordinary barrier when
+    /// the queues share a family.
+    fn record_ownership_transfer(
+        &self,
+        dst_stages: vk::PipelineStageFlags,
+        dst_access: vk::AccessFlags,
+        record: impl Fn(vk::CommandBuffer, vk::PipelineStageFlags, vk::PipelineStageFlags, vk::AccessFlags, vk::AccessFlags),
+    ) {
+        let src_stages = vk::PipelineStageFlags::TRANSFER;
+        let src_access = vk::AccessFlags::TRANSFER_WRITE;
+
+        if self.transfer_family == self.graphics_family {
+            record(self.transfer_commands, src_stages, dst_stages, src_access, dst_access);
+        } else {
+            // The access masks of the other queue are ignored, and the
+            // semaphore between the submissions makes the writes available.
+            record(self.transfer_commands, src_stages, vk::PipelineStageFlags::BOTTOM_OF_PIPE, src_access, vk::AccessFlags::empty());
+            record(self.graphics_commands, vk::PipelineStageFlags::TOP_OF_PIPE, dst_stages, vk::AccessFlags::empty(), dst_access);
+        }
+    }
+}
+
+unsafe fn create_staging_buffer(device: &SharedDevice, size: vk::DeviceSize) -> Result<Owned<vk::Buffer>> {
+    create_buffer(
+        device,
+        size,
+        vk::BufferUsageFlags::TRANSFER_SRC,
+        vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
+    )
+}